@@ -0,0 +1,246 @@
+use crate::backend::VaultBackend;
+use crate::clock::{Clock, SystemClock};
+use crate::error::TwofaError;
+use crate::logger::Logger;
+use crate::secret::VaultKey;
+use crate::twofa::create_code;
+use crate::vault::{Entry, Vault};
+
+// Commands on an unlocked storage. Where the storage is kept, how it was
+// unlocked and how the user is asked are up to the caller.
+
+/// Rejects secrets no code can be generated for.
+pub fn check_entry(entry: &Entry) -> Result<(), TwofaError> {
+    if entry.secret.expose().is_empty() {
+        return Err(TwofaError::InvalidSecret("secret must not be empty"));
+    }
+    create_code(entry, &SystemClock)?;
+    Ok(())
+}
+
+/// Adds `entry` to `vault` as `app`, changed at the time of `clock`, and
+/// saves it. `confirm` is asked before an existing application is replaced.
+#[allow(clippy::too_many_arguments)]
+pub fn set_secret(backend: &dyn VaultBackend, key: &VaultKey, vault: &mut Vault, app: &str, entry: Entry, confirm: impl FnOnce() -> Result<bool, TwofaError>, clock: &dyn Clock, logger: &Logger) -> Result<(), TwofaError> {
+    check_entry(&entry)?;
+
+    if vault.entries.contains_key(app) && !confirm()? {
+        return Err(TwofaError::Aborted);
+    }
+
+    vault.insert_entry(app, entry, clock.now());
+    backend.upsert_entry(key, vault, app, logger)
+}
+
+pub fn get_code(backend: &dyn VaultBackend, key: &VaultKey, app: &str, clock: &dyn Clock, logger: &Logger) -> Result<String, TwofaError> {
+    let entry = backend.load_entry(key, app, logger)?;
+    create_code(&entry, clock)
+}
+
+/// Saves an empty storage. `confirm` is asked before an existing storage is
+/// replaced and `new_key` only afterwards. Returns the key of the storage.
+pub fn create_storage(backend: &dyn VaultBackend, confirm: impl FnOnce() -> Result<bool, TwofaError>, new_key: impl FnOnce() -> Result<VaultKey, TwofaError>, logger: &Logger) -> Result<VaultKey, TwofaError> {
+    if backend.exists() && !confirm()? {
+        return Err(TwofaError::Aborted);
+    }
+
+    let key = new_key()?;
+    backend.replace(&key, &Vault::new(), logger)?;
+    Ok(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::MemoryBackend;
+    use crate::clock::FixedClock;
+    use crate::config::DefaultsConfig;
+    use crate::secret::SecretString;
+    use crate::test_util::{key, logger};
+    use crate::twofa::{Encoding, Hash};
+
+    // RFC 6238 test secret and codes for SHA1 with 8 digits
+    const RFC_SECRET: &str = "12345678901234567890";
+    const RFC_CODES: &[(u64, &str)] = &[
+        (1111111111, "14050471"),
+        (1234567890, "89005924"),
+        (2000000000, "69279037"),
+    ];
+
+    fn rfc_entry() -> Entry {
+        let mut entry = Entry::new(SecretString::from(RFC_SECRET), &DefaultsConfig::default());
+        entry.encoding = Encoding::Ascii;
+        entry.hash = Hash::Sha1;
+        entry.digits = 8;
+        entry
+    }
+
+    fn backend_with(apps: &[&str]) -> MemoryBackend {
+        let backend = MemoryBackend::default();
+        let mut vault = Vault::new();
+        for app in apps {
+            vault.entries.insert(app.to_string(), rfc_entry());
+        }
+        backend.save(&key(1), &vault, &logger()).unwrap();
+        backend
+    }
+
+    fn never_asked() -> Result<bool, TwofaError> {
+        panic!("confirmation was not expected");
+    }
+
+    #[test]
+    fn set_secret_adds_a_new_application() {
+        let backend = backend_with(&[]);
+        let mut vault = backend.load(&key(1), &logger()).unwrap();
+
+        set_secret(&backend, &key(1), &mut vault, "github", rfc_entry(), never_asked, &FixedClock(1111111111), &logger()).unwrap();
+
+        let stored = backend.load(&key(1), &logger()).unwrap();
+        assert_eq!(stored.entry("github").unwrap().secret.expose(), RFC_SECRET);
+        assert_eq!(stored.entry("github").unwrap().modified, 1111111111);
+    }
+
+    #[test]
+    fn set_secret_replaces_after_confirmation() {
+        let backend = backend_with(&["github"]);
+        let mut vault = backend.load(&key(1), &logger()).unwrap();
+        let mut entry = rfc_entry();
+        entry.window = 60;
+
+        set_secret(&backend, &key(1), &mut vault, "github", entry, || Ok(true), &FixedClock(1111111111), &logger()).unwrap();
+
+        assert_eq!(backend.load(&key(1), &logger()).unwrap().entry("github").unwrap().window, 60);
+    }
+
+    #[test]
+    fn set_secret_keeps_the_storage_when_not_confirmed() {
+        let backend = backend_with(&["github"]);
+        let mut vault = backend.load(&key(1), &logger()).unwrap();
+        let mut entry = rfc_entry();
+        entry.window = 60;
+
+        let result = set_secret(&backend, &key(1), &mut vault, "github", entry, || Ok(false), &FixedClock(1111111111), &logger());
+
+        assert!(matches!(result, Err(TwofaError::Aborted)));
+        assert_eq!(backend.load(&key(1), &logger()).unwrap().entry("github").unwrap().window, 30);
+    }
+
+    #[test]
+    fn set_secret_rejects_invalid_secrets() {
+        let backend = backend_with(&[]);
+        let mut vault = backend.load(&key(1), &logger()).unwrap();
+
+        let mut empty = rfc_entry();
+        empty.secret = SecretString::from("");
+        let mut not_base32 = rfc_entry();
+        not_base32.encoding = Encoding::Base32;
+        not_base32.secret = SecretString::from("not base32 !");
+
+        for entry in [empty, not_base32] {
+            let result = set_secret(&backend, &key(1), &mut vault, "github", entry, never_asked, &FixedClock(1111111111), &logger());
+            assert!(matches!(result, Err(TwofaError::InvalidSecret(_))));
+        }
+        assert!(backend.load(&key(1), &logger()).unwrap().entries.is_empty());
+    }
+
+    #[test]
+    fn check_entry_tells_unsupported_digits_from_invalid_secrets() {
+        let mut entry = rfc_entry();
+        entry.digits = 10;
+
+        assert!(matches!(check_entry(&entry), Err(TwofaError::Other(_))));
+    }
+
+    #[test]
+    fn set_secret_reports_failed_saves() {
+        let backend = MemoryBackend::failing();
+        let mut vault = Vault::new();
+
+        let result = set_secret(&backend, &key(1), &mut vault, "github", rfc_entry(), never_asked, &FixedClock(1111111111), &logger());
+
+        assert!(matches!(result, Err(TwofaError::Io(_))));
+        assert!(!backend.exists());
+    }
+
+    #[test]
+    fn get_code_follows_the_clock() {
+        let backend = backend_with(&["github"]);
+
+        for (time, code) in RFC_CODES {
+            assert_eq!(get_code(&backend, &key(1), "github", &FixedClock(*time), &logger()).unwrap(), *code);
+        }
+    }
+
+    #[test]
+    fn get_code_fails_for_unknown_applications() {
+        let backend = backend_with(&["github"]);
+
+        let result = get_code(&backend, &key(1), "gitlab", &FixedClock(0), &logger());
+
+        assert!(matches!(result, Err(TwofaError::EntryNotFound(app)) if app == "gitlab"));
+    }
+
+    #[test]
+    fn get_code_fails_with_the_wrong_key() {
+        let backend = backend_with(&["github"]);
+
+        let result = get_code(&backend, &key(2), "github", &FixedClock(0), &logger());
+
+        assert!(matches!(result, Err(TwofaError::WrongPassword)));
+    }
+
+    #[test]
+    fn get_code_fails_without_a_storage() {
+        let result = get_code(&MemoryBackend::default(), &key(1), "github", &FixedClock(0), &logger());
+
+        assert!(matches!(result, Err(TwofaError::VaultNotFound)));
+    }
+
+    #[test]
+    fn create_storage_saves_an_empty_storage() {
+        let backend = MemoryBackend::default();
+
+        create_storage(&backend, never_asked, || Ok(key(1)), &logger()).unwrap();
+
+        assert!(backend.load(&key(1), &logger()).unwrap().entries.is_empty());
+    }
+
+    #[test]
+    fn create_storage_replaces_after_confirmation() {
+        let backend = backend_with(&["github"]);
+
+        create_storage(&backend, || Ok(true), || Ok(key(2)), &logger()).unwrap();
+
+        assert!(backend.load(&key(2), &logger()).unwrap().entries.is_empty());
+    }
+
+    #[test]
+    fn create_storage_keeps_the_storage_when_not_confirmed() {
+        let backend = backend_with(&["github"]);
+
+        let result = create_storage(&backend, || Ok(false), || panic!("key was not expected"), &logger());
+
+        assert!(matches!(result, Err(TwofaError::Aborted)));
+        assert!(backend.load(&key(1), &logger()).unwrap().entries.contains_key("github"));
+    }
+
+    #[test]
+    fn create_storage_fails_without_a_key() {
+        let backend = MemoryBackend::default();
+
+        let result = create_storage(&backend, never_asked, || Err(TwofaError::Other("Passwords do not match")), &logger());
+
+        assert!(matches!(result, Err(TwofaError::Other(_))));
+        assert!(!backend.exists());
+    }
+
+    #[test]
+    fn create_storage_reports_failed_saves() {
+        let backend = MemoryBackend::failing();
+
+        let result = create_storage(&backend, never_asked, || Ok(key(1)), &logger());
+
+        assert!(matches!(result, Err(TwofaError::Io(_))));
+    }
+}
@@ -0,0 +1,1077 @@
+use std::cell::Cell;
+use std::fs::File;
+use std::io::Read;
+use rusqlite::{params, Connection, ErrorCode, OpenFlags, OptionalExtension};
+use serde::Serialize;
+use serde_json::{Map, Value};
+use crate::backup::create_backup;
+use crate::clock::{Clock, SystemClock};
+use crate::config::{BackupConfig, TwofaConfig};
+use crate::crypto::{encrypt_file, decrypt_file, decrypt_legacy_file, derive_subkey, matches_key_check, write_key_check, seal, open};
+use crate::error::TwofaError;
+use crate::helper::wipe_json;
+use crate::logger::Logger;
+use crate::migrate::SCHEMA_VERSION;
+use crate::secret::{SecretString, VaultKey};
+use crate::storage::{read_storage, save_storage, replace_storage, temp_path, delete_file, check_storage, Storage};
+use crate::vault::{Entry, Vault};
+
+const SQLITE_MAGIC: &[u8] = b"SQLite format 3\0";
+const SQLITE_FORMAT: &str = "1";
+const HEADER_CONTEXT: &[u8] = b"twofa sqlite header";
+const INDEX_CONTEXT: &[u8] = b"twofa sqlite index";
+const ENTRY_CONTEXT: &[u8] = b"twofa sqlite entry";
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum BackendKind {
+    /// the whole storage encrypted as one file, `twofa.storage`
+    File,
+    /// one encrypted row per application in `twofa.db`
+    Sqlite,
+}
+
+impl BackendKind {
+    pub fn from_name(name: &str) -> Result<Self, &'static str> {
+        match name {
+            "file" => Ok(BackendKind::File),
+            "sqlite" => Ok(BackendKind::Sqlite),
+            _ => Err("Storage backend must be \"file\" or \"sqlite\""),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            BackendKind::File => "file",
+            BackendKind::Sqlite => "sqlite",
+        }
+    }
+}
+
+pub fn take_entry(mut vault: Vault, name: &str) -> Result<Entry, TwofaError> {
+    match vault.entries.remove(name) {
+        Some(entry) => Ok(entry),
+        None => Err(TwofaError::EntryNotFound(name.to_owned())),
+    }
+}
+
+/// Where and how the encrypted storage is kept. Callers hold the storage
+/// lock, backends only read and write.
+pub trait VaultBackend {
+    fn kind(&self) -> BackendKind;
+
+    /// file holding the encrypted storage, also what gets backed up
+    fn path(&self) -> &str;
+
+    fn exists(&self) -> bool {
+        check_storage(self.path())
+    }
+
+    fn load(&self, key: &VaultKey, logger: &Logger) -> Result<Vault, TwofaError>;
+
+    /// Loads a single application. Backends that cannot read it on its
+    /// own decrypt everything.
+    fn load_entry(&self, key: &VaultKey, name: &str, logger: &Logger) -> Result<Entry, TwofaError> {
+        take_entry(self.load(key, logger)?, name)
+    }
+
+    /// Opens storages of earlier versions that need the password itself.
+    fn load_legacy(&self, _password: &SecretString, _logger: &Logger) -> Result<Vault, TwofaError> {
+        Err(TwofaError::WrongPassword)
+    }
+
+    /// Replaces the stored content with `vault`. Either all of it is
+    /// written or the previous content is kept.
+    fn save(&self, key: &VaultKey, vault: &Vault, logger: &Logger) -> Result<(), TwofaError>;
+
+    /// Like `save`, also over a storage written with another key, e.g. when
+    /// it is created again. Backends that always rewrite everything save.
+    fn replace(&self, key: &VaultKey, vault: &Vault, logger: &Logger) -> Result<(), TwofaError> {
+        self.save(key, vault, logger)
+    }
+    /// Stores `name` as `vault`, which already holds the change, has it.
+    /// Backends that cannot write it on its own save everything.
+    fn upsert_entry(&self, key: &VaultKey, vault: &Vault, _name: &str, logger: &Logger) -> Result<(), TwofaError> {
+        self.save(key, vault, logger)
+    }
+
+    /// Removes `name`, which `vault` no longer holds. Backends that cannot
+    /// remove it on its own save everything.
+    fn delete_entry(&self, key: &VaultKey, vault: &Vault, _name: &str, logger: &Logger) -> Result<(), TwofaError> {
+        self.save(key, vault, logger)
+    }
+
+    /// Moves `from` to `to`, which `vault` already has under its new name,
+    /// so that either both names change or neither. Backends that cannot
+    /// rename it on its own save everything.
+    fn rename_entry(&self, key: &VaultKey, vault: &Vault, _from: &str, _to: &str, logger: &Logger) -> Result<(), TwofaError> {
+        self.save(key, vault, logger)
+    }
+}
+
+/// The whole storage as one encrypted JSON file, decrypted through a
+/// buffer file next to it.
+pub struct FileBackend {
+    path: String,
+    buffer: String,
+    /// tells a wrong key from damaged content, if the file shares the key
+    /// of the storage directory
+    key_check: Option<String>,
+}
+
+impl FileBackend {
+    pub fn new(path: &str, storage_path: &Storage) -> Self {
+        Self {
+            path: path.to_owned(),
+            buffer: storage_path.de_file.clone(),
+            key_check: Some(storage_path.key_check.clone()),
+        }
+    }
+
+    fn without_key_check(mut self) -> Self {
+        self.key_check = None;
+        self
+    }
+
+    fn read_buffer(&self, logger: &Logger) -> Result<Vault, TwofaError> {
+        let data_from_file = match read_storage(&self.buffer[..]) {
+            Ok(data) => SecretString::new(data),
+            Err(_) => {
+                let _ = delete_file(&self.buffer[..], logger);
+                return Err(TwofaError::Io("Could not read decrypted storage"));
+            }
+        };
+        delete_file(&self.buffer[..], logger)?;
+
+        logger.min(
+            "storage_read",
+            format!("Data from file: \n {}", data_from_file.expose())
+                .as_str()
+        );
+
+        Vault::from_json(data_from_file.expose())
+    }
+
+    fn verify(&self, path: &str, expected: &SecretString, key: &VaultKey, logger: &Logger) -> Result<(), TwofaError> {
+        let verify_file = format!("{}.verify", &self.buffer);
+
+        let decrypted = decrypt_file(path, &verify_file[..], key, self.key_check.as_deref(), logger)
+            .and_then(|_| match read_storage(&verify_file[..]) {
+                Ok(data) => Ok(SecretString::new(data)),
+                Err(_) => Err(TwofaError::Io("Could not read verified storage")),
+            });
+        if check_storage(&verify_file[..]) {
+            delete_file(&verify_file[..], logger)?;
+        }
+
+        match decrypted {
+            Ok(data) if data == *expected => {
+                logger.min("storage_verified", "New storage decrypts to the written content");
+                Ok(())
+            },
+            _ => Err(TwofaError::Other("Written storage could not be verified, the previous one was kept")),
+        }
+    }
+}
+
+impl VaultBackend for FileBackend {
+    fn kind(&self) -> BackendKind {
+        BackendKind::File
+    }
+
+    fn path(&self) -> &str {
+        &self.path[..]
+    }
+
+    fn load(&self, key: &VaultKey, logger: &Logger) -> Result<Vault, TwofaError> {
+        decrypt_file(&self.path[..], &self.buffer[..], key, self.key_check.as_deref(), logger)?;
+        self.read_buffer(logger)
+    }
+
+    fn load_legacy(&self, password: &SecretString, logger: &Logger) -> Result<Vault, TwofaError> {
+        match decrypt_legacy_file(&self.path[..], &self.buffer[..], password, logger) {
+            Ok(()) => self.read_buffer(logger),
+            // no legacy key metadata, the header was fine for the current format
+            Err(TwofaError::CorruptVault(_)) => Err(TwofaError::WrongPassword),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// The new file is only moved over the storage once it decrypts to the
+    /// same content.
+    fn save(&self, key: &VaultKey, vault: &Vault, logger: &Logger) -> Result<(), TwofaError> {
+        let serialized_data = vault.to_json()?;
+
+        if save_storage(&self.buffer[..], serialized_data.expose()).is_err() {
+            return Err(TwofaError::Io("Could not save storage"));
+        }
+
+        let tmp = temp_path(&self.path[..]);
+        let encrypted = encrypt_file(&self.buffer[..], &tmp[..], key, logger);
+        delete_file(&self.buffer[..], logger)?;
+
+        if let Err(e) = encrypted.and_then(|_| self.verify(&tmp[..], &serialized_data, key, logger)) {
+            let _ = delete_file(&tmp[..], logger);
+            return Err(e);
+        }
+
+        if replace_storage(&tmp[..], &self.path[..]).is_err() {
+            return Err(TwofaError::Io("Could not replace storage, the previous one was kept"));
+        }
+
+        Ok(())
+    }
+}
+
+/// SQLite database with every application sealed in its own row and the
+/// rest of the storage sealed in `meta`. Nothing decrypted is written to disk
+/// and a change only touches the database inside one transaction.
+pub struct SqliteBackend {
+    path: String,
+    key_check: Option<String>,
+}
+
+#[derive(Serialize)]
+struct EntryRow<'a> {
+    name: &'a str,
+    entry: &'a Entry,
+}
+
+fn sqlite_error(e: rusqlite::Error) -> TwofaError {
+    match e {
+        rusqlite::Error::SqliteFailure(error, _) => match error.code {
+            ErrorCode::NotADatabase => TwofaError::CorruptVault("file is not a twofa storage"),
+            ErrorCode::DatabaseCorrupt => TwofaError::CorruptVault("database is damaged"),
+            ErrorCode::DatabaseBusy | ErrorCode::DatabaseLocked => TwofaError::Locked(None),
+            ErrorCode::CannotOpen | ErrorCode::PermissionDenied | ErrorCode::ReadOnly => TwofaError::Io("Could not open storage database"),
+            _ => TwofaError::Io("Could not access storage database"),
+        },
+        rusqlite::Error::QueryReturnedNoRows => TwofaError::CorruptVault("file is not a twofa storage"),
+        _ => TwofaError::Io("Could not access storage database"),
+    }
+}
+
+impl SqliteBackend {
+    pub fn new(path: &str, storage_path: &Storage) -> Self {
+        Self {
+            path: path.to_owned(),
+            key_check: Some(storage_path.key_check.clone()),
+        }
+    }
+
+    fn without_key_check(mut self) -> Self {
+        self.key_check = None;
+        self
+    }
+
+    fn connect(path: &str, flags: OpenFlags) -> Result<Connection, TwofaError> {
+        Connection::open_with_flags(path, flags).map_err(sqlite_error)
+    }
+
+    fn create_tables(conn: &Connection) -> Result<(), TwofaError> {
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS meta (name TEXT PRIMARY KEY, value BLOB NOT NULL);
+             CREATE TABLE IF NOT EXISTS entries (id INTEGER PRIMARY KEY, data BLOB NOT NULL);"
+        ).map_err(sqlite_error)
+    }
+
+    /// Writes `vault` to the open database in one transaction, committed
+    /// only once it reads back as `vault`.
+    fn write(&self, conn: &mut Connection, key: &VaultKey, vault: &Vault, logger: &Logger) -> Result<(), TwofaError> {
+        let header_key = derive_subkey(key, HEADER_CONTEXT);
+        let index_key = derive_subkey(key, INDEX_CONTEXT);
+        let entry_key = derive_subkey(key, ENTRY_CONTEXT);
+
+        let tx = conn.transaction().map_err(sqlite_error)?;
+        tx.execute("DELETE FROM entries", params![]).map_err(sqlite_error)?;
+        let mut index = Map::new();
+        for (id, (name, entry)) in vault.entries.iter().enumerate() {
+            let sealed = seal_row(&entry_key[..], name, entry)?;
+            tx.execute("INSERT INTO entries (id, data) VALUES (?1, ?2)", params![id as i64, sealed]).map_err(sqlite_error)?;
+            index.insert(name.clone(), Value::from(id));
+        }
+        tx.execute("INSERT OR REPLACE INTO meta (name, value) VALUES ('format', ?1)", params![SQLITE_FORMAT]).map_err(sqlite_error)?;
+        write_meta(&tx, &header_key[..], &index_key[..], vault, index)?;
+
+        let written = self.read_content(&tx, key, logger).and_then(|data| Vault::from_json(data.expose()));
+        match (written, vault.to_json()) {
+            (Ok(written), Ok(expected)) if written.to_json()? == expected => {
+                logger.min("storage_verified", "New database reads back as the written content");
+            },
+            _ => return Err(TwofaError::Other("Written storage could not be verified, the previous one was kept")),
+        }
+        tx.commit().map_err(sqlite_error)
+    }
+
+    /// Builds a new database next to the path and moves it there once
+    /// complete, whatever was there before.
+    fn create(&self, key: &VaultKey, vault: &Vault, logger: &Logger) -> Result<(), TwofaError> {
+        let flags = OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE;
+        let tmp = temp_path(&self.path[..]);
+        let _ = std::fs::remove_file(&tmp);
+
+        let written = Self::connect(&tmp[..], flags).and_then(|mut conn| {
+            Self::create_tables(&conn)?;
+            self.write(&mut conn, key, vault, logger)
+        });
+        if let Err(e) = written {
+            let _ = delete_file(&tmp[..], logger);
+            return Err(e);
+        }
+        if replace_storage(&tmp[..], &self.path[..]).is_err() {
+            return Err(TwofaError::Io("Could not create storage database"));
+        }
+
+        logger.min(
+            "storage_written",
+            format!("Wrote {} applications to '{}'", vault.entries.len(), &self.path)
+                .as_str()
+        );
+
+        Ok(())
+    }
+
+    /// Writes each of `names` as `vault` has it, or removes it, together
+    /// with the header and index in one transaction, committed only once
+    /// they read back as written. The other rows are not touched.
+    fn write_entries(&self, key: &VaultKey, vault: &Vault, names: &[&str], logger: &Logger) -> Result<(), TwofaError> {
+        if !self.exists() {
+            return self.save(key, vault, logger);
+        }
+
+        let mut conn = self.connect_checked(OpenFlags::SQLITE_OPEN_READ_WRITE, logger)?;
+        // also makes sure the rows are not sealed with another key than the rest
+        let header = self.read_header(&conn, key, logger)?;
+        let mut index = match self.read_index(&conn, key, &header)? {
+            Some(index) => index,
+            None => return self.save(key, vault, logger),
+        };
+
+        let header_key = derive_subkey(key, HEADER_CONTEXT);
+        let index_key = derive_subkey(key, INDEX_CONTEXT);
+        let entry_key = derive_subkey(key, ENTRY_CONTEXT);
+
+        let tx = conn.transaction().map_err(sqlite_error)?;
+        for name in names {
+            let id = index.get(*name).and_then(|id| id.as_i64());
+            match (vault.entries.get(*name), id) {
+                (Some(entry), Some(id)) => {
+                    let sealed = seal_row(&entry_key[..], name, entry)?;
+                    tx.execute("UPDATE entries SET data = ?2 WHERE id = ?1", params![id, sealed]).map_err(sqlite_error)?;
+                },
+                (Some(entry), None) => {
+                    let sealed = seal_row(&entry_key[..], name, entry)?;
+                    tx.execute("INSERT INTO entries (data) VALUES (?1)", params![sealed]).map_err(sqlite_error)?;
+                    index.insert((*name).to_owned(), Value::from(tx.last_insert_rowid()));
+                },
+                (None, Some(id)) => {
+                    tx.execute("DELETE FROM entries WHERE id = ?1", params![id]).map_err(sqlite_error)?;
+                    index.remove(*name);
+                },
+                (None, None) => {},
+            }
+        }
+        write_meta(&tx, &header_key[..], &index_key[..], vault, index)?;
+
+        let index = self.read_index(&tx, key, &self.read_header(&tx, key, logger)?)?.unwrap_or_default();
+        for name in names {
+            let written = self.read_row(&tx, key, &index, name)?;
+            if serde_json::to_value(&written).ok() != serde_json::to_value(vault.entries.get(*name)).ok() {
+                return Err(TwofaError::Other("Written storage could not be verified, the previous one was kept"));
+            }
+        }
+        tx.commit().map_err(sqlite_error)?;
+
+        logger.min(
+            "storage_written",
+            format!("Wrote application(s) '{}' to '{}'", names.join("', '"), &self.path)
+                .as_str()
+        );
+
+        Ok(())
+    }
+
+    /// A header that does not open means a wrong key, unless the key
+    /// matches the stored check.
+    fn header_error(&self, key: &VaultKey, logger: &Logger) -> TwofaError {
+        match self.key_check.as_deref().and_then(|path| matches_key_check(path, key)) {
+            Some(true) => {
+                logger.min("decrypt_corrupt", "Key matches the stored check, content is damaged");
+                TwofaError::CorruptVault("content does not match its checksum")
+            },
+            _ => TwofaError::WrongPassword,
+        }
+    }
+
+    fn connect_readonly(&self, logger: &Logger) -> Result<Connection, TwofaError> {
+        self.connect_checked(OpenFlags::SQLITE_OPEN_READ_ONLY, logger)
+    }
+
+    /// Opens the existing database, if it has a format this version knows.
+    fn connect_checked(&self, flags: OpenFlags, logger: &Logger) -> Result<Connection, TwofaError> {
+        logger.min(
+            "decrypt",
+            format!("Open database '{}'", &self.path)
+                .as_str()
+        );
+
+        let conn = Self::connect(&self.path[..], flags)?;
+        let format: Option<String> = conn
+            .query_row("SELECT value FROM meta WHERE name = 'format'", params![], |row| row.get(0))
+            .optional()
+            .map_err(sqlite_error)?;
+        match format.as_deref() {
+            Some(SQLITE_FORMAT) => Ok(conn),
+            Some(_) => Err(TwofaError::Other("Storage database format is not supported. Update twofa to open it")),
+            None => Err(TwofaError::CorruptVault("file is not a twofa storage")),
+        }
+    }
+
+    /// Opens the header, which also tells whether `key` is the right one.
+    fn read_header(&self, conn: &Connection, key: &VaultKey, logger: &Logger) -> Result<Map<String, Value>, TwofaError> {
+        let header = match read_meta(conn, "header")? {
+            Some(header) => header,
+            None => return Err(TwofaError::CorruptVault("file is not a twofa storage")),
+        };
+        let header_key = derive_subkey(key, HEADER_CONTEXT);
+        let header = match open(&header_key[..], HEADER_CONTEXT, &header[..]) {
+            Ok(header) => header,
+            Err(_) => return Err(self.header_error(key, logger)),
+        };
+
+        match serde_json::from_slice(&header[..]) {
+            Ok(header) => Ok(header),
+            Err(_) => Err(TwofaError::CorruptVault("could not parse storage")),
+        }
+    }
+
+    /// Opens the map of names to rows. `None` if the content is older than
+    /// this version and has to go through the migrations first.
+    fn read_index(&self, conn: &Connection, key: &VaultKey, header: &Map<String, Value>) -> Result<Option<Map<String, Value>>, TwofaError> {
+        let index = match (header.get("version").and_then(|v| v.as_u64()), read_meta(conn, "index")?) {
+            (Some(version), Some(index)) if version == SCHEMA_VERSION as u64 => index,
+            _ => return Ok(None),
+        };
+        let index_key = derive_subkey(key, INDEX_CONTEXT);
+        let index = match open(&index_key[..], INDEX_CONTEXT, &index[..]) {
+            Ok(index) => index,
+            Err(_) => return Err(TwofaError::CorruptVault("index does not match its checksum")),
+        };
+
+        match serde_json::from_slice(&index[..]) {
+            Ok(index) => Ok(Some(index)),
+            Err(_) => Err(TwofaError::CorruptVault("could not parse index")),
+        }
+    }
+
+    /// Opens the header and every row into the JSON of a file storage.
+    fn read_content(&self, conn: &Connection, key: &VaultKey, logger: &Logger) -> Result<SecretString, TwofaError> {
+        let mut raw = self.read_header(conn, key, logger)?;
+
+        let entry_key = derive_subkey(key, ENTRY_CONTEXT);
+        let mut entries = Map::new();
+        let mut stmt = conn.prepare("SELECT data FROM entries ORDER BY id").map_err(sqlite_error)?;
+        let rows = stmt.query_map(params![], |row| row.get::<_, Vec<u8>>(0)).map_err(sqlite_error)?;
+        for sealed in rows {
+            let (name, entry) = open_row(&entry_key[..], &sealed.map_err(sqlite_error)?[..])?;
+            entries.insert(name, entry);
+        }
+        raw.insert(String::from("entries"), Value::Object(entries));
+
+        // parsed through the same migrations as the file storage
+        let mut raw = Value::Object(raw);
+        let data = SecretString::new(raw.to_string());
+        wipe_json(&mut raw);
+        Ok(data)
+    }
+
+    /// Opens the row `index` has for `name`, `None` if it has none.
+    fn read_row(&self, conn: &Connection, key: &VaultKey, index: &Map<String, Value>, name: &str) -> Result<Option<Entry>, TwofaError> {
+        let id = match index.get(name).and_then(|id| id.as_i64()) {
+            Some(id) => id,
+            None => return Ok(None),
+        };
+
+        let sealed: Option<Vec<u8>> = conn
+            .query_row("SELECT data FROM entries WHERE id = ?1", params![id], |row| row.get(0))
+            .optional()
+            .map_err(sqlite_error)?;
+        let sealed = match sealed {
+            Some(sealed) => sealed,
+            None => return Err(TwofaError::CorruptVault("index refers to a missing application")),
+        };
+        let entry_key = derive_subkey(key, ENTRY_CONTEXT);
+        let (row_name, mut raw) = open_row(&entry_key[..], &sealed[..])?;
+        if row_name != name {
+            wipe_json(&mut raw);
+            return Err(TwofaError::CorruptVault("index does not match the applications"));
+        }
+
+        let entry: Entry = match serde_json::from_value(raw) {
+            Ok(entry) => entry,
+            Err(_) => return Err(TwofaError::CorruptVault("storage does not match the schema")),
+        };
+        entry.validate()?;
+        Ok(Some(entry))
+    }
+}
+
+fn read_meta(conn: &Connection, name: &str) -> Result<Option<Vec<u8>>, TwofaError> {
+    conn.query_row("SELECT value FROM meta WHERE name = ?1", params![name], |row| row.get(0))
+        .optional()
+        .map_err(sqlite_error)
+}
+
+fn seal_row(entry_key: &[u8], name: &str, entry: &Entry) -> Result<Vec<u8>, TwofaError> {
+    let row = match serde_json::to_string(&EntryRow { name, entry }) {
+        Ok(row) => SecretString::new(row),
+        Err(_) => return Err(TwofaError::Other("Could not serialize storage")),
+    };
+    Ok(seal(entry_key, ENTRY_CONTEXT, row.expose().as_bytes())?)
+}
+
+/// Seals the header of `vault` and the name index into `meta`.
+fn write_meta(conn: &Connection, header_key: &[u8], index_key: &[u8], vault: &Vault, index: Map<String, Value>) -> Result<(), TwofaError> {
+    let header = seal(header_key, HEADER_CONTEXT, Value::Object(vault.header()).to_string().as_bytes())?;
+    let index = seal(index_key, INDEX_CONTEXT, Value::Object(index).to_string().as_bytes())?;
+    conn.execute("INSERT OR REPLACE INTO meta (name, value) VALUES ('header', ?1)", params![header]).map_err(sqlite_error)?;
+    conn.execute("INSERT OR REPLACE INTO meta (name, value) VALUES ('index', ?1)", params![index]).map_err(sqlite_error)?;
+    Ok(())
+}
+
+/// Opens one row and returns the application name with its raw settings.
+fn open_row(entry_key: &[u8], sealed: &[u8]) -> Result<(String, Value), TwofaError> {
+    let row = match open(entry_key, ENTRY_CONTEXT, sealed) {
+        Ok(row) => row,
+        Err(_) => return Err(TwofaError::CorruptVault("application does not match its checksum")),
+    };
+    let mut row: Value = match serde_json::from_slice(&row[..]) {
+        Ok(row) => row,
+        Err(_) => return Err(TwofaError::CorruptVault("could not parse application")),
+    };
+
+    match (row["name"].as_str().map(|name| name.to_owned()), row["entry"].take()) {
+        (Some(name), entry) if entry.is_object() => Ok((name, entry)),
+        (_, mut entry) => {
+            wipe_json(&mut entry);
+            wipe_json(&mut row);
+            Err(TwofaError::CorruptVault("application without a name"))
+        },
+    }
+}
+
+impl VaultBackend for SqliteBackend {
+    fn kind(&self) -> BackendKind {
+        BackendKind::Sqlite
+    }
+
+    fn path(&self) -> &str {
+        &self.path[..]
+    }
+
+    fn load(&self, key: &VaultKey, logger: &Logger) -> Result<Vault, TwofaError> {
+        let conn = self.connect_readonly(logger)?;
+        let data = self.read_content(&conn, key, logger)?;
+
+        logger.min(
+            "storage_read",
+            format!("Data from database: \n {}", data.expose())
+                .as_str()
+        );
+
+        Vault::from_json(data.expose())
+    }
+
+    /// Only opens the header, the name index and the row of `name`.
+    fn load_entry(&self, key: &VaultKey, name: &str, logger: &Logger) -> Result<Entry, TwofaError> {
+        let conn = self.connect_readonly(logger)?;
+        let header = self.read_header(&conn, key, logger)?;
+
+        // older content has to go through the migrations first
+        let index = match self.read_index(&conn, key, &header)? {
+            Some(index) => index,
+            None => return take_entry(self.load(key, logger)?, name),
+        };
+        let entry = match self.read_row(&conn, key, &index, name)? {
+            Some(entry) => entry,
+            None => return Err(TwofaError::EntryNotFound(name.to_owned())),
+        };
+
+        logger.min(
+            "storage_read",
+            format!("Read application '{}' from database", name)
+                .as_str()
+        );
+
+        Ok(entry)
+    }
+
+    /// A new database is built next to the path and moved there once
+    /// complete. An existing one is changed in a transaction, after its
+    /// header showed that `key` is the one it was written with.
+    fn save(&self, key: &VaultKey, vault: &Vault, logger: &Logger) -> Result<(), TwofaError> {
+        if !self.exists() {
+            return self.create(key, vault, logger);
+        }
+
+        let mut conn = self.connect_checked(OpenFlags::SQLITE_OPEN_READ_WRITE, logger)?;
+        self.read_header(&conn, key, logger)?;
+        self.write(&mut conn, key, vault, logger)?;
+
+        logger.min(
+            "storage_written",
+            format!("Wrote {} applications to '{}'", vault.entries.len(), &self.path)
+                .as_str()
+        );
+
+        Ok(())
+    }
+
+    fn replace(&self, key: &VaultKey, vault: &Vault, logger: &Logger) -> Result<(), TwofaError> {
+        self.create(key, vault, logger)
+    }
+
+    fn upsert_entry(&self, key: &VaultKey, vault: &Vault, name: &str, logger: &Logger) -> Result<(), TwofaError> {
+        self.write_entries(key, vault, &[name], logger)
+    }
+
+    fn delete_entry(&self, key: &VaultKey, vault: &Vault, name: &str, logger: &Logger) -> Result<(), TwofaError> {
+        self.write_entries(key, vault, &[name], logger)
+    }
+
+    fn rename_entry(&self, key: &VaultKey, vault: &Vault, from: &str, to: &str, logger: &Logger) -> Result<(), TwofaError> {
+        self.write_entries(key, vault, &[from, to], logger)
+    }
+}
+
+/// Backend of a storage directory. Before every save the current content
+/// is backed up, afterwards the key check is updated.
+pub struct StorageBackend<'a> {
+    backend: Box<dyn VaultBackend>,
+    storage_path: &'a Storage,
+    backup: &'a BackupConfig,
+    clock: &'a dyn Clock,
+    /// whether this command already backed up the storage
+    backed_up: Cell<bool>,
+}
+
+impl<'a> StorageBackend<'a> {
+    /// Backs up the storage before the first change of the command.
+    fn back_up(&self, logger: &Logger) -> Result<(), TwofaError> {
+        if !self.backed_up.get() {
+            create_backup(self.storage_path, self.backend.path(), self.backup, self.clock, logger)?;
+            self.backed_up.set(true);
+        }
+        Ok(())
+    }
+}
+
+impl<'a> VaultBackend for StorageBackend<'a> {
+    fn kind(&self) -> BackendKind {
+        self.backend.kind()
+    }
+
+    fn path(&self) -> &str {
+        self.backend.path()
+    }
+
+    fn exists(&self) -> bool {
+        self.backend.exists()
+    }
+
+    fn load(&self, key: &VaultKey, logger: &Logger) -> Result<Vault, TwofaError> {
+        self.backend.load(key, logger)
+    }
+
+    fn load_entry(&self, key: &VaultKey, name: &str, logger: &Logger) -> Result<Entry, TwofaError> {
+        self.backend.load_entry(key, name, logger)
+    }
+
+    fn load_legacy(&self, password: &SecretString, logger: &Logger) -> Result<Vault, TwofaError> {
+        self.backend.load_legacy(password, logger)
+    }
+
+    fn save(&self, key: &VaultKey, vault: &Vault, logger: &Logger) -> Result<(), TwofaError> {
+        self.back_up(logger)?;
+        self.backend.save(key, vault, logger)?;
+        write_key_check(&self.storage_path.key_check[..], key)
+    }
+
+    fn replace(&self, key: &VaultKey, vault: &Vault, logger: &Logger) -> Result<(), TwofaError> {
+        self.back_up(logger)?;
+        self.backend.replace(key, vault, logger)?;
+        write_key_check(&self.storage_path.key_check[..], key)
+    }
+
+    fn upsert_entry(&self, key: &VaultKey, vault: &Vault, name: &str, logger: &Logger) -> Result<(), TwofaError> {
+        self.back_up(logger)?;
+        self.backend.upsert_entry(key, vault, name, logger)?;
+        write_key_check(&self.storage_path.key_check[..], key)
+    }
+
+    fn delete_entry(&self, key: &VaultKey, vault: &Vault, name: &str, logger: &Logger) -> Result<(), TwofaError> {
+        self.back_up(logger)?;
+        self.backend.delete_entry(key, vault, name, logger)?;
+        write_key_check(&self.storage_path.key_check[..], key)
+    }
+
+    fn rename_entry(&self, key: &VaultKey, vault: &Vault, from: &str, to: &str, logger: &Logger) -> Result<(), TwofaError> {
+        self.back_up(logger)?;
+        self.backend.rename_entry(key, vault, from, to, logger)?;
+        write_key_check(&self.storage_path.key_check[..], key)
+    }
+}
+
+/// Keeps the storage in memory, for tests.
+#[cfg(test)]
+#[derive(Default)]
+pub struct MemoryBackend {
+    /// key bytes and content of the last save
+    stored: std::cell::RefCell<Option<(Vec<u8>, String)>>,
+    fail_save: bool,
+}
+
+#[cfg(test)]
+impl MemoryBackend {
+    /// A backend whose saves fail like on a full disk.
+    pub fn failing() -> Self {
+        Self {
+            fail_save: true,
+            ..Self::default()
+        }
+    }
+}
+
+#[cfg(test)]
+impl VaultBackend for MemoryBackend {
+    fn kind(&self) -> BackendKind {
+        BackendKind::File
+    }
+
+    fn path(&self) -> &str {
+        ":memory:"
+    }
+
+    fn exists(&self) -> bool {
+        self.stored.borrow().is_some()
+    }
+
+    fn load(&self, key: &VaultKey, _logger: &Logger) -> Result<Vault, TwofaError> {
+        match &*self.stored.borrow() {
+            Some((stored_key, data)) if stored_key[..] == key.expose()[..] => Vault::from_json(&data[..]),
+            Some(_) => Err(TwofaError::WrongPassword),
+            None => Err(TwofaError::VaultNotFound),
+        }
+    }
+
+    fn save(&self, key: &VaultKey, vault: &Vault, _logger: &Logger) -> Result<(), TwofaError> {
+        if self.fail_save {
+            return Err(TwofaError::Io("Could not save storage"));
+        }
+
+        let data = vault.to_json()?;
+        *self.stored.borrow_mut() = Some((key.expose().to_vec(), data.expose().to_owned()));
+        Ok(())
+    }
+}
+
+pub fn backend_of_kind(kind: BackendKind, storage_path: &Storage) -> Box<dyn VaultBackend> {
+    match kind {
+        BackendKind::File => Box::new(FileBackend::new(&storage_path.en_file[..], storage_path)),
+        BackendKind::Sqlite => Box::new(SqliteBackend::new(&storage_path.db_file[..], storage_path)),
+    }
+}
+
+/// The backend of the existing storage, or the configured one for a new
+/// storage.
+pub fn open_backend(storage_path: &Storage, config: &TwofaConfig) -> Result<Box<dyn VaultBackend>, TwofaError> {
+    Ok(backend_of_kind(backend_kind(storage_path, config)?, storage_path))
+}
+
+/// Like `open_backend`, backing up and recording the key on every save.
+pub fn storage_backend<'a>(storage_path: &'a Storage, config: &'a TwofaConfig) -> Result<StorageBackend<'a>, TwofaError> {
+    Ok(StorageBackend {
+        backend: open_backend(storage_path, config)?,
+        storage_path,
+        backup: &config.backup,
+        clock: &SystemClock,
+        backed_up: Cell::new(false),
+    })
+}
+
+fn backend_kind(storage_path: &Storage, config: &TwofaConfig) -> Result<BackendKind, TwofaError> {
+    let kind = if check_storage(&storage_path.db_file[..]) {
+        BackendKind::Sqlite
+    } else if check_storage(&storage_path.en_file[..]) {
+        BackendKind::File
+    } else {
+        BackendKind::from_name(&config.storage.backend[..])?
+    };
+
+    Ok(kind)
+}
+
+/// Moves the storage from `current` to a new backend of `kind`, backing up
+/// both first. The old storage is only removed once the new one opens with
+/// the same content.
+#[allow(clippy::too_many_arguments)]
+pub fn move_to_backend(current: &dyn VaultBackend, kind: BackendKind, storage_path: &Storage, key: &VaultKey, vault: &Vault, backup: &BackupConfig, clock: &dyn Clock, logger: &Logger) -> Result<Box<dyn VaultBackend>, TwofaError> {
+    let target = backend_of_kind(kind, storage_path);
+    if target.exists() {
+        // left behind by an earlier move, keep a copy before replacing it
+        create_backup(storage_path, target.path(), backup, clock, logger)?;
+        delete_file(target.path(), logger)?;
+    }
+
+    create_backup(storage_path, current.path(), backup, clock, logger)?;
+    target.save(key, vault, logger)?;
+
+    let matches = match target.load(key, logger) {
+        Ok(written) => written.to_json()? == vault.to_json()?,
+        Err(_) => false,
+    };
+    if !matches {
+        let _ = delete_file(target.path(), logger);
+        return Err(TwofaError::Other("Migrated storage could not be verified, the previous one was kept"));
+    }
+
+    delete_file(current.path(), logger)?;
+    Ok(target)
+}
+
+/// Backend able to read the storage copy at `path`, e.g. a backup.
+pub fn backend_for_file(path: &str, storage_path: &Storage) -> Box<dyn VaultBackend> {
+    if is_sqlite_file(path) {
+        Box::new(SqliteBackend::new(path, storage_path))
+    } else {
+        Box::new(FileBackend::new(path, storage_path))
+    }
+}
+
+/// Like `backend_for_file`, for copies that may use another key than the
+/// storage directory, e.g. one written on another machine.
+pub fn backend_for_foreign_file(path: &str, storage_path: &Storage) -> Box<dyn VaultBackend> {
+    if is_sqlite_file(path) {
+        Box::new(SqliteBackend::new(path, storage_path).without_key_check())
+    } else {
+        Box::new(FileBackend::new(path, storage_path).without_key_check())
+    }
+}
+
+fn is_sqlite_file(path: &str) -> bool {
+    let mut magic = [0u8; 16];
+    File::open(path)
+        .and_then(|mut file| file.read_exact(&mut magic))
+        .map(|_| magic[..] == *SQLITE_MAGIC)
+        .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::DefaultsConfig;
+    use crate::test_util::{key, logger, storage_dir};
+
+    fn vault(apps: &[&str]) -> Vault {
+        let mut vault = Vault::new();
+        for app in apps {
+            vault.insert_entry(app, Entry::new(SecretString::from("JBSWY3DPEHPK3PXP"), &DefaultsConfig::default()), 1000);
+        }
+        vault
+    }
+
+    fn rows(backend: &SqliteBackend) -> Vec<(i64, Vec<u8>)> {
+        let conn = Connection::open(&backend.path).unwrap();
+        let mut stmt = conn.prepare("SELECT id, data FROM entries ORDER BY id").unwrap();
+        let rows = stmt.query_map(params![], |row| Ok((row.get(0)?, row.get(1)?))).unwrap();
+        rows.map(|row| row.unwrap()).collect()
+    }
+
+    #[test]
+    fn sqlite_upsert_only_writes_the_changed_row() {
+        let storage_path = storage_dir("backend-upsert");
+        let backend = SqliteBackend::new(&storage_path.db_file[..], &storage_path);
+        let mut vault = vault(&["aws", "github", "gitlab"]);
+        backend.save(&key(7), &vault, &logger()).unwrap();
+        let before = rows(&backend);
+
+        let mut entry = Entry::new(SecretString::from("GEZDGNBV"), &DefaultsConfig::default());
+        entry.window = 60;
+        vault.insert_entry("github", entry, 2000);
+        vault.insert_entry("npm", Entry::new(SecretString::from("MFRGGZDF"), &DefaultsConfig::default()), 2000);
+        backend.upsert_entry(&key(7), &vault, "github", &logger()).unwrap();
+        backend.upsert_entry(&key(7), &vault, "npm", &logger()).unwrap();
+
+        let after = rows(&backend);
+        assert_eq!(after.len(), 4);
+        assert_eq!(after[0], before[0]);
+        assert_ne!(after[1], before[1]);
+        assert_eq!(after[2], before[2]);
+        assert_eq!(backend.load(&key(7), &logger()).unwrap().to_json().unwrap(), vault.to_json().unwrap());
+        assert_eq!(backend.load_entry(&key(7), "npm", &logger()).unwrap().secret.expose(), "MFRGGZDF");
+
+        std::fs::remove_dir_all(&storage_path.dir).unwrap();
+    }
+
+    #[test]
+    fn foreign_files_ignore_the_key_check_of_the_storage() {
+        let storage_path = storage_dir("backend-foreign");
+        let other = key(9);
+        crate::crypto::write_key_check(&storage_path.key_check[..], &key(7)).unwrap();
+        let copy = format!("{}/copy", &storage_path.dir);
+        FileBackend::new(&copy[..], &storage_path).save(&other, &vault(&["github"]), &logger()).unwrap();
+
+        let result = backend_for_foreign_file(&copy[..], &storage_path).load(&key(7), &logger());
+        assert!(matches!(result, Err(TwofaError::WrongPassword)));
+        assert!(backend_for_foreign_file(&copy[..], &storage_path).load(&other, &logger()).is_ok());
+
+        std::fs::remove_dir_all(&storage_path.dir).unwrap();
+    }
+
+    fn timed<T>(what: &str, f: impl Fn() -> T) {
+        let runs = 10;
+        let start = std::time::Instant::now();
+        for _ in 0..runs {
+            f();
+        }
+        eprintln!("{:<32} {:?}", what, start.elapsed() / runs);
+    }
+
+    /// Compares reading one application and everything from both
+    /// backends, without key derivation. Run with
+    /// `cargo test --release compares_backends -- --ignored --nocapture`.
+    #[test]
+    #[ignore]
+    fn compares_backends() {
+        let storage_path = storage_dir("backend-bench");
+        let apps: Vec<String> = (0..2000).map(|i| format!("app{}", i)).collect();
+        let vault = vault(&apps.iter().map(|app| &app[..]).collect::<Vec<&str>>());
+        let file = FileBackend::new(&storage_path.en_file[..], &storage_path);
+        let sqlite = SqliteBackend::new(&storage_path.db_file[..], &storage_path);
+        file.save(&key(7), &vault, &logger()).unwrap();
+        sqlite.save(&key(7), &vault, &logger()).unwrap();
+
+        timed("file: one of 2000", || file.load_entry(&key(7), "app1999", &logger()).unwrap());
+        timed("sqlite: one of 2000", || sqlite.load_entry(&key(7), "app1999", &logger()).unwrap());
+        timed("file: all 2000", || file.load(&key(7), &logger()).unwrap());
+        timed("sqlite: all 2000", || sqlite.load(&key(7), &logger()).unwrap());
+
+        std::fs::remove_dir_all(&storage_path.dir).unwrap();
+    }
+
+    #[test]
+    fn sqlite_delete_only_removes_the_row() {
+        let storage_path = storage_dir("backend-delete");
+        let backend = SqliteBackend::new(&storage_path.db_file[..], &storage_path);
+        let mut vault = vault(&["aws", "github", "gitlab"]);
+        backend.save(&key(7), &vault, &logger()).unwrap();
+        let before = rows(&backend);
+
+        vault.remove_entry("github", 2000);
+        backend.delete_entry(&key(7), &vault, "github", &logger()).unwrap();
+
+        assert_eq!(rows(&backend), vec![before[0].clone(), before[2].clone()]);
+        let loaded = backend.load(&key(7), &logger()).unwrap();
+        assert_eq!(loaded.deleted.get("github"), Some(&2000));
+        assert!(matches!(backend.load_entry(&key(7), "github", &logger()), Err(TwofaError::EntryNotFound(_))));
+        assert_eq!(backend.load_entry(&key(7), "gitlab", &logger()).unwrap().secret.expose(), "JBSWY3DPEHPK3PXP");
+
+        std::fs::remove_dir_all(&storage_path.dir).unwrap();
+    }
+
+    #[test]
+    fn sqlite_rename_moves_the_row_in_one_step() {
+        let storage_path = storage_dir("backend-rename");
+        let backend = SqliteBackend::new(&storage_path.db_file[..], &storage_path);
+        let mut vault = vault(&["aws", "github"]);
+        backend.save(&key(7), &vault, &logger()).unwrap();
+
+        let entry = vault.remove_entry("github", 2000).unwrap();
+        vault.insert_entry("gh", entry, 2000);
+        backend.rename_entry(&key(7), &vault, "github", "gh", &logger()).unwrap();
+
+        assert_eq!(rows(&backend).len(), 2);
+        assert!(matches!(backend.load_entry(&key(7), "github", &logger()), Err(TwofaError::EntryNotFound(_))));
+        assert_eq!(backend.load(&key(7), &logger()).unwrap().to_json().unwrap(), vault.to_json().unwrap());
+
+        std::fs::remove_dir_all(&storage_path.dir).unwrap();
+    }
+
+    #[test]
+    fn sqlite_save_keeps_a_storage_written_with_another_key() {
+        let storage_path = storage_dir("backend-other-key");
+        let backend = SqliteBackend::new(&storage_path.db_file[..], &storage_path);
+        backend.save(&key(7), &vault(&["aws", "github"]), &logger()).unwrap();
+
+        assert!(matches!(backend.save(&key(9), &vault(&[]), &logger()), Err(TwofaError::WrongPassword)));
+        assert_eq!(backend.load(&key(7), &logger()).unwrap().entries.len(), 2);
+
+        // unless it is explicitly replaced
+        backend.replace(&key(9), &vault(&[]), &logger()).unwrap();
+        assert!(backend.load(&key(9), &logger()).unwrap().entries.is_empty());
+
+        std::fs::remove_dir_all(&storage_path.dir).unwrap();
+    }
+
+    #[test]
+    fn backs_up_once_per_command() {
+        let storage_path = storage_dir("backend-one-backup");
+        let backup = BackupConfig { enabled: true, keep: 10, max_age_days: 0 };
+        let file = FileBackend::new(&storage_path.en_file[..], &storage_path);
+        let mut vault = vault(&["github"]);
+        file.save(&key(7), &vault, &logger()).unwrap();
+
+        let backend = StorageBackend {
+            backend: Box::new(file),
+            storage_path: &storage_path,
+            backup: &backup,
+            clock: &crate::clock::FixedClock(1625412600),
+            backed_up: Cell::new(false),
+        };
+        let entry = vault.remove_entry("github", 2000).unwrap();
+        vault.insert_entry("gh", entry, 2000);
+        backend.rename_entry(&key(7), &vault, "github", "gh", &logger()).unwrap();
+        vault.remove_entry("gh", 2001);
+        backend.delete_entry(&key(7), &vault, "gh", &logger()).unwrap();
+
+        assert_eq!(crate::backup::list_backups(&storage_path).unwrap().len(), 1);
+        std::fs::remove_dir_all(&storage_path.dir).unwrap();
+    }
+
+    #[test]
+    fn moves_file_storages_to_sqlite() {
+        let storage_path = storage_dir("backend-move");
+        let backup = BackupConfig { enabled: true, keep: 10, max_age_days: 0 };
+        let file = FileBackend::new(&storage_path.en_file[..], &storage_path);
+        let vault = vault(&["aws", "github"]);
+        file.save(&key(7), &vault, &logger()).unwrap();
+
+        let target = move_to_backend(&file, BackendKind::Sqlite, &storage_path, &key(7), &vault, &backup, &crate::clock::FixedClock(1625412600), &logger()).unwrap();
+
+        assert!(!file.exists());
+        assert_eq!(target.path(), storage_path.db_file);
+        assert_eq!(target.load_entry(&key(7), "github", &logger()).unwrap().secret.expose(), "JBSWY3DPEHPK3PXP");
+        // the file is kept as a backup
+        assert_eq!(crate::backup::list_backups(&storage_path).unwrap().len(), 1);
+
+        std::fs::remove_dir_all(&storage_path.dir).unwrap();
+    }
+
+    #[test]
+    fn file_save_keeps_the_storage_when_it_can_not_be_verified() {
+        let storage_path = storage_dir("backend-verify");
+        let backend = FileBackend::new(&storage_path.en_file[..], &storage_path);
+        backend.save(&key(7), &vault(&["github"]), &logger()).unwrap();
+        // the written file can not be decrypted for the check
+        let verify_file = format!("{}.verify", &storage_path.de_file);
+        std::fs::create_dir(&verify_file).unwrap();
+
+        assert!(backend.save(&key(7), &vault(&["aws", "github"]), &logger()).is_err());
+
+        std::fs::remove_dir(&verify_file).unwrap();
+        assert_eq!(backend.load(&key(7), &logger()).unwrap().entries.len(), 1);
+        assert!(!check_storage(&temp_path(&storage_path.en_file)));
+        assert!(!check_storage(&storage_path.de_file));
+
+        std::fs::remove_dir_all(&storage_path.dir).unwrap();
+    }
+}
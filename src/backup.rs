@@ -0,0 +1,220 @@
+use std::fs::{metadata, read_dir};
+use crate::clock::Clock;
+use crate::config::BackupConfig;
+use crate::error::TwofaError;
+use crate::logger::{format_timestamp, parse_timestamp, Logger};
+use crate::storage::{copy_storage, check_storage, create_private_dir, delete_file, Storage};
+
+const BACKUP_EXTENSION: &str = ".storage";
+
+pub struct Backup {
+    pub id: String,
+    pub path: String,
+    /// seconds since epoch
+    pub created: u64,
+    pub size: u64,
+}
+
+fn backup_path(storage_path: &Storage, id: &str) -> String {
+    format!("{}/{}{}", &storage_path.backup_dir, id, BACKUP_EXTENSION)
+}
+
+/// Creation time and counter of the backup `id`, `None` if the name was
+/// not given by `new_id`.
+fn parse_id(id: &str) -> Option<(u64, u32)> {
+    let (time, counter) = match id.split_once('-') {
+        Some((time, counter)) => (time, counter.parse::<u32>().ok()?),
+        None => (id, 0),
+    };
+    if time.len() != 16 || !time.is_ascii() {
+        return None;
+    }
+
+    let timestamp = format!("{}-{}-{}:{}:{}", &time[..4], &time[4..6], &time[6..11], &time[11..13], &time[13..]);
+    Some((parse_timestamp(&timestamp)?, counter))
+}
+
+/// Ids are the creation time, e.g. `20210704T153000Z`, with a counter
+/// appended for further backups within the same second.
+fn new_id(storage_path: &Storage, clock: &dyn Clock) -> Result<String, TwofaError> {
+    let id: String = format_timestamp(clock.now())
+        .chars()
+        .filter(|c| *c != '-' && *c != ':')
+        .collect();
+
+    let prefix = format!("{}-", id);
+    let last = list_backups(storage_path)?
+        .iter()
+        .filter_map(|b| {
+            if b.id == id {
+                Some(0)
+            } else if b.id.starts_with(&prefix) {
+                b.id[prefix.len()..].parse::<u32>().ok()
+            } else {
+                None
+            }
+        })
+        .max();
+
+    match last {
+        Some(n) => Ok(format!("{}{}", prefix, n + 1)),
+        None => Ok(id),
+    }
+}
+
+/// Copies the storage file at `path` to the backup directory and removes
+/// backups beyond the configured retention. Returns the id of the new backup.
+pub fn create_backup(storage_path: &Storage, path: &str, config: &BackupConfig, clock: &dyn Clock, logger: &Logger) -> Result<Option<String>, TwofaError> {
+    if !config.enabled || !check_storage(path) {
+        return Ok(None);
+    }
+
+    if create_private_dir(&storage_path.backup_dir[..]).is_err() {
+        return Err(TwofaError::Io("Could not create backup directory"));
+    }
+
+    let id = new_id(storage_path, clock)?;
+    if copy_storage(path, &backup_path(storage_path, &id)[..]).is_err() {
+        return Err(TwofaError::Io("Could not back up storage"));
+    }
+
+    logger.min(
+        "backup_created",
+        format!("Created backup '{}'", &id)
+            .as_str()
+    );
+
+    prune_backups(storage_path, config, clock, logger)?;
+
+    Ok(Some(id))
+}
+
+/// Returns all backups, oldest first.
+pub fn list_backups(storage_path: &Storage) -> Result<Vec<Backup>, TwofaError> {
+    if !check_storage(&storage_path.backup_dir[..]) {
+        return Ok(Vec::new());
+    }
+
+    let entries = match read_dir(&storage_path.backup_dir) {
+        Ok(entries) => entries,
+        Err(_) => return Err(TwofaError::Io("Could not read backup directory")),
+    };
+
+    let mut backups: Vec<(u32, Backup)> = Vec::new();
+    for entry in entries.flatten() {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let id = match name.strip_suffix(BACKUP_EXTENSION) {
+            Some(id) => id.to_owned(),
+            None => continue,
+        };
+        // the id is the time on the clock that made it, whatever the file
+        // system says about the copy
+        let (created, counter) = match parse_id(&id) {
+            Some(parsed) => parsed,
+            None => continue,
+        };
+
+        let path = entry.path().to_string_lossy().into_owned();
+        let size = match metadata(&path) {
+            Ok(m) => m.len(),
+            Err(_) => continue,
+        };
+
+        backups.push((counter, Backup { id, path, created, size }));
+    }
+
+    backups.sort_by_key(|(counter, backup)| (backup.created, *counter));
+    Ok(backups.into_iter().map(|(_, backup)| backup).collect())
+}
+
+pub fn find_backup(storage_path: &Storage, id: &str) -> Result<Backup, TwofaError> {
+    match list_backups(storage_path)?.into_iter().find(|b| b.id == id) {
+        Some(backup) => Ok(backup),
+        None => Err(TwofaError::BackupNotFound(id.to_owned())),
+    }
+}
+
+fn prune_backups(storage_path: &Storage, config: &BackupConfig, clock: &dyn Clock, logger: &Logger) -> Result<(), TwofaError> {
+    let backups = list_backups(storage_path)?;
+    let count = backups.len();
+    let oldest_allowed = clock.now().saturating_sub(config.max_age_days * 86400);
+
+    for (i, backup) in backups.iter().enumerate() {
+        // the newest backup is kept regardless of its age, 0 keeps any number
+        let too_many = config.keep > 0 && count - i > config.keep as usize;
+        let too_old = config.max_age_days > 0 && backup.created < oldest_allowed && i + 1 < count;
+
+        if (too_many || too_old) && delete_file(&backup.path[..], logger).is_ok() {
+            logger.min(
+                "backup_pruned",
+                format!("Removed backup '{}'", &backup.id)
+                    .as_str()
+            );
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::FixedClock;
+    use crate::test_util::{logger, storage_dir};
+
+    // 2021-07-04T15:30:00Z
+    const NOW: u64 = 1625412600;
+
+    /// A storage directory with a storage file to back up.
+    fn storage_with_file(name: &str) -> Storage {
+        let storage_path = storage_dir(name);
+        std::fs::write(&storage_path.en_file, "encrypted").unwrap();
+        storage_path
+    }
+
+    fn ids(storage_path: &Storage) -> Vec<String> {
+        list_backups(storage_path).unwrap().into_iter().map(|b| b.id).collect()
+    }
+
+    #[test]
+    fn names_backups_after_the_clock() {
+        let storage_path = storage_with_file("backup-ids");
+        let config = BackupConfig { keep: 10, max_age_days: 0, ..BackupConfig::default() };
+
+        for _ in 0..3 {
+            create_backup(&storage_path, &storage_path.en_file[..], &config, &FixedClock(NOW), &logger()).unwrap();
+        }
+
+        assert_eq!(ids(&storage_path), vec!["20210704T153000Z", "20210704T153000Z-1", "20210704T153000Z-2"]);
+        std::fs::remove_dir_all(&storage_path.dir).unwrap();
+    }
+
+    #[test]
+    fn prunes_backups_older_than_the_clock_allows() {
+        let storage_path = storage_with_file("backup-prune");
+        let config = BackupConfig { keep: 10, max_age_days: 30, ..BackupConfig::default() };
+
+        create_backup(&storage_path, &storage_path.en_file[..], &config, &FixedClock(NOW), &logger()).unwrap();
+        create_backup(&storage_path, &storage_path.en_file[..], &config, &FixedClock(NOW + 86400), &logger()).unwrap();
+        assert_eq!(ids(&storage_path).len(), 2);
+
+        // 30 days after the second backup, only the first one is too old
+        create_backup(&storage_path, &storage_path.en_file[..], &config, &FixedClock(NOW + 31 * 86400), &logger()).unwrap();
+
+        assert_eq!(ids(&storage_path), vec!["20210705T153000Z", "20210804T153000Z"]);
+        std::fs::remove_dir_all(&storage_path.dir).unwrap();
+    }
+
+    #[test]
+    fn keeps_every_backup_when_keep_is_0() {
+        let storage_path = storage_with_file("backup-unlimited");
+        let config = BackupConfig { keep: 0, max_age_days: 0, ..BackupConfig::default() };
+
+        for _ in 0..3 {
+            create_backup(&storage_path, &storage_path.en_file[..], &config, &FixedClock(NOW), &logger()).unwrap();
+        }
+
+        assert_eq!(ids(&storage_path).len(), 3);
+        std::fs::remove_dir_all(&storage_path.dir).unwrap();
+    }
+}
@@ -0,0 +1,327 @@
+use clap::{AppSettings, Clap};
+use crate::config::DIGITS;
+use crate::secret::SecretString;
+
+#[derive(Clap)]
+#[clap(version = "1.0.0", author = "Paul D. <paullenardo@gmail.com>")]
+#[clap(about = "Save TOTP secrets and generate codes")]
+#[clap(setting = AppSettings::ColoredHelp)]
+#[clap(setting = AppSettings::SubcommandRequiredElseHelp)]
+#[clap(setting = AppSettings::VersionlessSubcommands)]
+pub struct Opts {
+    #[clap(subcommand)]
+    pub command: Command,
+    #[clap(short, long, global = true, parse(from_str))]
+    /// provide password, prompted for if missing
+    pub password: Option<SecretString>,
+    #[clap(short, long, global = true, parse(from_occurrences))]
+    /// set debug level
+    pub debug: i32,
+    #[clap(long, global = true)]
+    /// print secrets in debug output on the terminal, the log file stays redacted
+    pub debug_unsafe: bool,
+    #[clap(long, global = true)]
+    /// directory of the storage, overrides TWOFA_VAULT
+    pub vault: Option<String>,
+    #[clap(long, global = true)]
+    /// profile to use, overrides TWOFA_PROFILE
+    pub profile: Option<String>,
+    #[clap(long, global = true, possible_values = &["text", "json"])]
+    /// output format of get, show and list [default: output.format]
+    pub format: Option<String>,
+    #[clap(long, global = true, possible_values = &["text", "json"])]
+    /// log format
+    pub log_format: Option<String>,
+}
+
+#[derive(Clap)]
+pub enum Command {
+    /// Create a new, empty storage
+    #[clap(after_help = "EXAMPLES:\n    twofa init")]
+    Init,
+    /// Add the secret of an application
+    #[clap(alias = "set")]
+    #[clap(after_help = "EXAMPLES:\n    twofa add github\n    twofa add github --secret JBSWY3DPEHPK3PXP\n    twofa add aws --hash sha256 --window 60 --issuer \"Amazon Web Services\"")]
+    Add(AddOpts),
+    /// Print the current code of an application
+    #[clap(after_help = "EXAMPLES:\n    twofa get github\n    twofa get github --copy")]
+    Get(GetOpts),
+    /// Show the settings of an application
+    #[clap(after_help = "EXAMPLES:\n    twofa show github\n    twofa show github --reveal")]
+    Show(ShowOpts),
+    /// List all applications without their secrets
+    #[clap(alias = "ls")]
+    #[clap(after_help = "EXAMPLES:\n    twofa list")]
+    List,
+    /// Remove an application
+    #[clap(alias = "remove")]
+    #[clap(after_help = "EXAMPLES:\n    twofa rm github\n    twofa rm github --force")]
+    Rm(RmOpts),
+    /// Rename an application
+    #[clap(after_help = "EXAMPLES:\n    twofa rename githb github")]
+    Rename(RenameOpts),
+    /// Import applications from a plaintext JSON export
+    #[clap(after_help = "EXAMPLES:\n    twofa import backup.json\n    twofa import backup.json --force")]
+    Import(ImportOpts),
+    /// Export all applications as plaintext JSON
+    #[clap(after_help = "EXAMPLES:\n    twofa export\n    twofa export --output backup.json")]
+    Export(ExportOpts),
+    /// List or restore automatic backups of the storage
+    #[clap(after_help = "EXAMPLES:\n    twofa backup list\n    twofa backup restore 20210704T153000Z")]
+    Backup(BackupOpts),
+    /// Print a session token to export as TWOFA_SESSION
+    #[clap(after_help = "EXAMPLES:\n    eval \"$(twofa unlock)\"")]
+    Unlock,
+    /// Revoke the cached key and end the current session
+    #[clap(after_help = "EXAMPLES:\n    twofa lock")]
+    Lock,
+    /// List, create or delete profiles with their own storage
+    #[clap(after_help = "EXAMPLES:\n    twofa profile list\n    twofa profile create work\n    twofa --profile work add github\n    twofa profile delete work")]
+    Profile(ProfileOpts),
+    /// Show or change settings of config.toml
+    #[clap(after_help = "EXAMPLES:\n    twofa config list\n    twofa config get defaults.hash\n    twofa config set defaults.digits 8\n    twofa --profile work config set clipboard.copy true --local")]
+    Config(ConfigOpts),
+    /// Check the permissions of the storage directory
+    #[clap(after_help = "EXAMPLES:\n    twofa doctor\n    twofa doctor --fix")]
+    Doctor(DoctorOpts),
+    /// List or restore earlier versions of the storage kept with git
+    #[clap(after_help = "EXAMPLES:\n    twofa config set history.enabled true\n    twofa history\n    twofa history restore 3f2a9c1")]
+    History(HistoryOpts),
+    /// Move the storage to another backend
+    #[clap(after_help = "EXAMPLES:\n    twofa migrate-backend sqlite\n    twofa migrate-backend file")]
+    MigrateBackend(MigrateBackendOpts),
+    /// Merge another copy of the storage, e.g. a sync conflict, into this one
+    #[clap(after_help = "EXAMPLES:\n    twofa merge ~/.local/share/twofa/twofa.sync-conflict-20240101-120000.db\n    twofa merge --prefer newer twofa.conflict.db")]
+    Merge(MergeOpts),
+    /// Show how the applications of two storages, exports or backups differ
+    #[clap(after_help = "EXAMPLES:\n    twofa diff 20210704T153000Z ~/.local/share/twofa/twofa.db\n    twofa diff twofa.sync-conflict-20240101-120000.db backup.json --format json")]
+    Diff(DiffOpts),
+}
+
+#[derive(Clap)]
+pub struct AddOpts {
+    /// name of application
+    pub application: String,
+    #[clap(short, long, parse(from_str))]
+    /// secret of application, prompted for if missing
+    pub secret: Option<SecretString>,
+    #[clap(short, long, parse(try_from_str = parse_window))]
+    /// period in seconds a code is valid [default: defaults.period]
+    pub window: Option<u32>,
+    #[clap(long, possible_values = &["sha1", "sha256", "sha512"])]
+    /// hash function [default: defaults.hash]
+    pub hash: Option<String>,
+    #[clap(short, long, possible_values = &["base32", "hex", "ascii"])]
+    /// encoding of secret [default: defaults.encoding]
+    pub encoding: Option<String>,
+    #[clap(long, parse(try_from_str = parse_digits))]
+    /// number of digits of codes, 6 to 9 [default: defaults.digits]
+    pub digits: Option<u32>,
+    #[clap(short, long)]
+    /// service the account belongs to, shown by `list`
+    pub issuer: Option<String>,
+    #[clap(short, long)]
+    /// overwrite an existing application without asking
+    pub force: bool,
+}
+
+#[derive(Clap)]
+pub struct GetOpts {
+    /// name of application
+    pub application: String,
+    #[clap(short, long)]
+    /// copy code to clipboard
+    pub copy: bool,
+}
+
+#[derive(Clap)]
+pub struct ShowOpts {
+    /// name of application
+    pub application: String,
+    #[clap(short, long)]
+    /// also print the secret
+    pub reveal: bool,
+}
+
+#[derive(Clap)]
+pub struct RmOpts {
+    /// name of application
+    pub application: String,
+    #[clap(short, long)]
+    /// remove without asking
+    pub force: bool,
+}
+
+#[derive(Clap)]
+pub struct RenameOpts {
+    /// current name of application
+    pub old: String,
+    /// new name of application
+    pub new: String,
+}
+
+#[derive(Clap)]
+pub struct ImportOpts {
+    /// JSON file created by `twofa export`
+    pub file: String,
+    #[clap(short, long)]
+    /// overwrite existing applications
+    pub force: bool,
+}
+
+#[derive(Clap)]
+pub struct ExportOpts {
+    #[clap(short, long)]
+    /// write to file instead of stdout
+    pub output: Option<String>,
+}
+
+#[derive(Clap)]
+pub struct BackupOpts {
+    #[clap(subcommand)]
+    pub command: BackupCommand,
+}
+
+#[derive(Clap)]
+pub enum BackupCommand {
+    /// List backups, oldest first
+    List,
+    /// Replace the storage with a backup
+    Restore(RestoreOpts),
+}
+
+#[derive(Clap)]
+pub struct RestoreOpts {
+    /// id of the backup as shown by `backup list`
+    pub id: String,
+}
+
+#[derive(Clap)]
+pub struct HistoryOpts {
+    #[clap(subcommand)]
+    pub command: Option<HistoryCommand>,
+}
+
+#[derive(Clap)]
+pub enum HistoryCommand {
+    /// List recorded changes, newest first
+    List,
+    /// Replace the storage with its version at a revision
+    Restore(HistoryRestoreOpts),
+}
+
+#[derive(Clap)]
+pub struct HistoryRestoreOpts {
+    /// revision as shown by `history`
+    pub rev: String,
+}
+
+#[derive(Clap)]
+pub struct ProfileOpts {
+    #[clap(subcommand)]
+    pub command: ProfileCommand,
+}
+
+#[derive(Clap)]
+pub enum ProfileCommand {
+    /// List profiles, the active one is marked with *
+    List,
+    /// Create a profile with a new storage and password
+    Create(ProfileCreateOpts),
+    /// Delete a profile with its storage, backups and config
+    Delete(ProfileDeleteOpts),
+}
+
+#[derive(Clap)]
+pub struct ProfileCreateOpts {
+    /// name of profile
+    pub name: String,
+}
+
+#[derive(Clap)]
+pub struct ProfileDeleteOpts {
+    /// name of profile
+    pub name: String,
+    #[clap(short, long)]
+    /// delete without asking
+    pub force: bool,
+}
+
+#[derive(Clap)]
+pub struct ConfigOpts {
+    #[clap(subcommand)]
+    pub command: ConfigCommand,
+}
+
+#[derive(Clap)]
+pub enum ConfigCommand {
+    /// List all settings with the values in effect
+    List,
+    /// Print the value in effect of a setting
+    Get(ConfigGetOpts),
+    /// Change a setting
+    Set(ConfigSetOpts),
+}
+
+#[derive(Clap)]
+pub struct ConfigGetOpts {
+    /// setting as section.key, e.g. defaults.hash
+    pub key: String,
+}
+
+#[derive(Clap)]
+pub struct ConfigSetOpts {
+    /// setting as section.key, e.g. defaults.hash
+    pub key: String,
+    /// new value
+    pub value: String,
+    #[clap(long)]
+    /// change the config of the active profile instead of the global one
+    pub local: bool,
+}
+
+#[derive(Clap)]
+pub struct DoctorOpts {
+    #[clap(long)]
+    /// restrict files and directories to their owner
+    pub fix: bool,
+}
+
+#[derive(Clap)]
+pub struct MigrateBackendOpts {
+    #[clap(possible_values = &["file", "sqlite"])]
+    /// backend to move the storage to
+    pub backend: String,
+}
+
+#[derive(Clap)]
+pub struct MergeOpts {
+    /// encrypted storage to merge into this one
+    pub other: String,
+    #[clap(long, possible_values = &["ours", "theirs", "newer"])]
+    /// side to keep for applications changed in both, asked for if missing
+    pub prefer: Option<String>,
+}
+
+#[derive(Clap)]
+pub struct DiffOpts {
+    /// storage, export or backup id to compare from
+    pub a: String,
+    /// storage, export or backup id to compare to
+    pub b: String,
+}
+
+fn parse_digits(value: &str) -> Result<u32, String> {
+    match value.parse::<u32>() {
+        Ok(d) if DIGITS.contains(&d) => Ok(d),
+        _ => Err(format!("digits must be between {} and {}", DIGITS.start(), DIGITS.end())),
+    }
+}
+
+fn parse_window(value: &str) -> Result<u32, String> {
+    match value.parse::<u32>() {
+        Ok(0) => Err(String::from("window must be greater than 0")),
+        Ok(w) => Ok(w),
+        Err(_) => Err(format!("'{}' is not a number of seconds", value)),
+    }
+}
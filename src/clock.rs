@@ -0,0 +1,30 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Source of the current time, for codes, backup ids and retention and
+/// session expiry.
+pub trait Clock {
+    /// seconds since epoch
+    fn now(&self) -> u64;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0)
+    }
+}
+
+/// Always returns the same time.
+#[cfg(test)]
+pub struct FixedClock(pub u64);
+
+#[cfg(test)]
+impl Clock for FixedClock {
+    fn now(&self) -> u64 {
+        self.0
+    }
+}
@@ -0,0 +1,385 @@
+use std::ops::RangeInclusive;
+use serde::{Deserialize, Serialize};
+use crate::profile::DEFAULT_PROFILE;
+use crate::storage::{read_storage, save_storage, check_storage};
+use crate::twofa::{Encoding, Hash};
+
+/// number of digits a code may have
+pub const DIGITS: RangeInclusive<u32> = 6..=9;
+
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct KeyCacheConfig {
+    /// cache the derived vault key in the kernel keyring
+    pub enabled: bool,
+    /// seconds until the cached key expires
+    pub timeout: u32,
+    /// "session" or "user"
+    pub keyring: String,
+}
+
+impl Default for KeyCacheConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            timeout: 300,
+            keyring: String::from("session"),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct SessionConfig {
+    /// seconds until a session token from `unlock` expires
+    pub timeout: u64,
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        Self {
+            timeout: 3600,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct LogConfig {
+    /// "text" or "json"
+    pub format: String,
+    /// also write logs to <home>/logs/twofa.log
+    pub file: bool,
+    /// bytes after which the log file is rotated
+    pub max_size: u64,
+    /// number of rotated log files to keep
+    pub max_files: u32,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        Self {
+            format: String::from("text"),
+            file: false,
+            max_size: 1024 * 1024,
+            max_files: 5,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct LockConfig {
+    /// seconds to wait for other twofa processes using the storage
+    pub timeout: u64,
+}
+
+impl Default for LockConfig {
+    fn default() -> Self {
+        Self {
+            timeout: 10,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct BackupConfig {
+    /// back up the storage before every change
+    pub enabled: bool,
+    /// number of backups to keep, 0 keeps any number
+    pub keep: u32,
+    /// days after which backups are removed, 0 keeps them forever
+    pub max_age_days: u64,
+}
+
+impl Default for BackupConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            keep: 10,
+            max_age_days: 90,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Default)]
+#[serde(default)]
+pub struct HistoryConfig {
+    /// commit the encrypted storage to a git repository in its directory
+    /// after every change
+    pub enabled: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct PermissionsConfig {
+    /// refuse to run when the storage or its directory is accessible by
+    /// other users, instead of only warning
+    pub strict: bool,
+}
+
+impl Default for PermissionsConfig {
+    fn default() -> Self {
+        Self {
+            strict: true,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct StorageConfig {
+    /// "file" or "sqlite". A storage kept in the other one is moved the
+    /// next time `get`, `show` or a change unlocks it
+    pub backend: String,
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        Self {
+            backend: String::from("sqlite"),
+        }
+    }
+}
+
+/// Settings of applications added without giving them. The only place
+/// these defaults are defined.
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct DefaultsConfig {
+    pub hash: Hash,
+    pub digits: u32,
+    /// seconds a code is valid
+    pub period: u32,
+    pub encoding: Encoding,
+}
+
+impl Default for DefaultsConfig {
+    fn default() -> Self {
+        Self {
+            hash: Hash::Sha1,
+            digits: 6,
+            period: 30,
+            encoding: Encoding::Base32,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Default)]
+#[serde(default)]
+pub struct ClipboardConfig {
+    /// copy the code of `get` without `--copy`
+    pub copy: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct OutputConfig {
+    /// "text" or "json", for `get`, `show` and `list`
+    pub format: String,
+}
+
+impl Default for OutputConfig {
+    fn default() -> Self {
+        Self {
+            format: String::from("text"),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct TwofaConfig {
+    /// profile used without `--profile`, only read from the global config
+    pub default_profile: String,
+    pub defaults: DefaultsConfig,
+    pub clipboard: ClipboardConfig,
+    pub output: OutputConfig,
+    pub storage: StorageConfig,
+    pub key_cache: KeyCacheConfig,
+    pub session: SessionConfig,
+    pub log: LogConfig,
+    pub lock: LockConfig,
+    pub backup: BackupConfig,
+    pub history: HistoryConfig,
+    pub permissions: PermissionsConfig,
+}
+
+impl Default for TwofaConfig {
+    fn default() -> Self {
+        Self {
+            default_profile: String::from(DEFAULT_PROFILE),
+            defaults: DefaultsConfig::default(),
+            clipboard: ClipboardConfig::default(),
+            output: OutputConfig::default(),
+            storage: StorageConfig::default(),
+            key_cache: KeyCacheConfig::default(),
+            session: SessionConfig::default(),
+            log: LogConfig::default(),
+            lock: LockConfig::default(),
+            backup: BackupConfig::default(),
+            history: HistoryConfig::default(),
+            permissions: PermissionsConfig::default(),
+        }
+    }
+}
+
+impl TwofaConfig {
+    /// Checks values the types alone do not restrict.
+    pub fn validate(&self) -> Result<(), &'static str> {
+        if !DIGITS.contains(&self.defaults.digits) {
+            return Err("defaults.digits must be between 6 and 9");
+        }
+        if self.defaults.period == 0 {
+            return Err("defaults.period must be greater than 0");
+        }
+        if self.output.format != "text" && self.output.format != "json" {
+            return Err("output.format must be \"text\" or \"json\"");
+        }
+        if self.storage.backend != "file" && self.storage.backend != "sqlite" {
+            return Err("storage.backend must be \"file\" or \"sqlite\"");
+        }
+        if self.log.format != "text" && self.log.format != "json" {
+            return Err("log.format must be \"text\" or \"json\"");
+        }
+
+        Ok(())
+    }
+}
+
+fn read_toml(path: &str) -> Result<Option<toml::Value>, &'static str> {
+    if !check_storage(path) {
+        return Ok(None);
+    }
+
+    let data = match read_storage(path) {
+        Ok(data) => data,
+        Err(_) => return Err("Could not read config file"),
+    };
+
+    match toml::from_str(data.as_str()) {
+        Ok(value) => Ok(Some(value)),
+        Err(_) => Err("Could not parse config file"),
+    }
+}
+
+/// Overrides the keys of `base` with those of `over`, table by table.
+fn merge_toml(base: &mut toml::Value, over: toml::Value) {
+    match (base, over) {
+        (toml::Value::Table(base), toml::Value::Table(over)) => {
+            for (key, value) in over {
+                match base.get_mut(&key) {
+                    Some(existing) => merge_toml(existing, value),
+                    None => {
+                        base.insert(key, value);
+                    },
+                }
+            }
+        },
+        (base, over) => *base = over,
+    }
+}
+
+/// Reads the config at `path`, overridden by the config of the profile
+/// if one is given.
+pub fn read_config(path: &str, profile_path: Option<&str>) -> Result<TwofaConfig, &'static str> {
+    let mut config = read_toml(path)?.unwrap_or_else(|| toml::Value::Table(toml::value::Table::new()));
+    if let Some(profile_path) = profile_path {
+        if let Some(profile) = read_toml(profile_path)? {
+            merge_toml(&mut config, profile);
+        }
+    }
+
+    let config: TwofaConfig = match config.try_into() {
+        Ok(config) => config,
+        Err(_) => return Err("Could not parse config file"),
+    };
+    config.validate()?;
+    Ok(config)
+}
+
+/// Every setting with its value as `(section.key, value)`.
+pub fn config_entries(config: &TwofaConfig) -> Result<Vec<(String, toml::Value)>, &'static str> {
+    let value = match toml::Value::try_from(config) {
+        Ok(value) => value,
+        Err(_) => return Err("Could not serialize config"),
+    };
+
+    let mut entries = Vec::new();
+    flatten_toml(String::new(), value, &mut entries);
+    Ok(entries)
+}
+
+fn flatten_toml(prefix: String, value: toml::Value, entries: &mut Vec<(String, toml::Value)>) {
+    match value {
+        toml::Value::Table(table) => {
+            for (key, value) in table {
+                let key = if prefix.is_empty() { key } else { format!("{}.{}", prefix, key) };
+                flatten_toml(key, value, entries);
+            }
+        },
+        value => entries.push((prefix, value)),
+    }
+}
+
+/// Parses `value` like the current value of `key` is typed.
+fn parse_config_value(key: &str, value: &str) -> Result<toml::Value, &'static str> {
+    let current = match config_entries(&TwofaConfig::default())?.into_iter().find(|(k, _)| k == key) {
+        Some((_, current)) => current,
+        None => return Err("Unknown config key. See 'twofa config list'"),
+    };
+
+    match current {
+        toml::Value::Boolean(_) => match value {
+            "true" => Ok(toml::Value::Boolean(true)),
+            "false" => Ok(toml::Value::Boolean(false)),
+            _ => Err("Value must be true or false"),
+        },
+        toml::Value::Integer(_) => match value.parse::<u32>() {
+            Ok(n) => Ok(toml::Value::Integer(n as i64)),
+            Err(_) => Err("Value must be a positive number"),
+        },
+        _ => Ok(toml::Value::String(value.to_owned())),
+    }
+}
+
+/// Sets `key` in the config file at `path`, keeping its other settings.
+/// Comments in the file are not kept.
+pub fn set_config_value(path: &str, key: &str, value: &str) -> Result<(), &'static str> {
+    let value = parse_config_value(key, value)?;
+    let mut file = read_toml(path)?.unwrap_or_else(|| toml::Value::Table(toml::value::Table::new()));
+
+    let mut table = match file.as_table_mut() {
+        Some(table) => table,
+        None => return Err("Could not parse config file"),
+    };
+    let mut parts: Vec<&str> = key.split('.').collect();
+    let last = parts.pop().unwrap_or(key);
+    for part in parts {
+        table = match table
+            .entry(part.to_owned())
+            .or_insert_with(|| toml::Value::Table(toml::value::Table::new()))
+            .as_table_mut() {
+            Some(table) => table,
+            None => return Err("Could not parse config file"),
+        };
+    }
+    table.insert(last.to_owned(), value);
+
+    // reject values the config would not load with
+    let config: TwofaConfig = match file.clone().try_into() {
+        Ok(config) => config,
+        Err(_) => return Err("Invalid value for this key"),
+    };
+    config.validate()?;
+
+    let data = match toml::to_string(&file) {
+        Ok(data) => data,
+        Err(_) => return Err("Could not serialize config"),
+    };
+    match save_storage(path, &data[..]) {
+        Ok(_) => Ok(()),
+        Err(_) => Err("Could not write config file"),
+    }
+}
@@ -1,34 +1,83 @@
+use std::rc::Rc;
 use encryptfile as ef;
-use encryptfile::{ EncryptError };
+use encryptfile::{ EncryptError, PwKeyArray };
+use zeroize::{ Zeroize, Zeroizing };
+use ring::aead::{ Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305, NONCE_LEN };
+use ring::rand::{ SecureRandom, SystemRandom };
+use ring::{ digest, hmac };
 use crate::logger::Logger;
+use crate::secret::{ SecretString, VaultKey };
+use crate::error::TwofaError;
+use crate::storage::{ read_storage, save_storage, check_storage };
 
-pub fn encrypt_file(in_path: &str, out_path: &str, password: &str, logger: &Logger) -> Result<(), &'static str> {
+pub const KEY_SIZE: usize = ef::PW_KEY_SIZE;
+pub const SEAL_KEY_SIZE: usize = 32;
+const KEY_CHECK_CONTEXT: &[u8] = b"twofa key check";
+
+pub fn derive_key(password: &SecretString, logger: &Logger) -> Result<VaultKey, &'static str> {
+    logger.min("derive_key", "Deriving key from password");
+
+    let mut c = ef::Config::new();
+    c.password(ef::PasswordType::Text(password.expose().to_owned(), ef::scrypt_defaults()));
+    match c.derive_key() {
+        Ok(key) => Ok(VaultKey::new(key)),
+        Err(_) => Err("Could not derive key from password"),
+    }
+}
+
+pub fn key_from_bytes(bytes: &[u8]) -> Option<VaultKey> {
+    if bytes.len() != KEY_SIZE {
+        return None;
+    }
+
+    let mut key: PwKeyArray = [0; KEY_SIZE];
+    key.copy_from_slice(bytes);
+    let vault_key = VaultKey::new(key);
+    key.zeroize();
+    Some(vault_key)
+}
+
+fn key_password(key: &VaultKey) -> ef::PasswordType {
+    let key = Zeroizing::new(*key.expose());
+    ef::PasswordType::Func(Rc::new(Box::new(move || *key)))
+}
+
+pub fn encrypt_file(in_path: &str, out_path: &str, key: &VaultKey, logger: &Logger) -> Result<(), TwofaError> {
     logger.min(
+        "encrypt",
         format!("Encrypting '{}' to '{}'", &in_path, &out_path)
             .as_str()
     );
 
+    // the key is derived with the fixed scrypt defaults, so no key metadata is written
     let mut c = ef::Config::new();
     c.input_stream(ef::InputStream::File(in_path.to_owned()))
         .output_stream(ef::OutputStream::File(out_path.to_owned()))
         .add_output_option(ef::OutputOption::AllowOverwrite)
+        .remove_output_option(ef::OutputOption::IncludeKeyMetadata)
         .initialization_vector(ef::InitializationVector::GenerateFromRng)
-        .password(ef::PasswordType::Text(password.to_owned(), ef::scrypt_defaults()))
+        .password(key_password(key))
         .encrypt();
     match ef::process(&c) {
         Ok(()) => Ok(()),
         Err(e) => {
             match e {
+                EncryptError::IoError(_error) => {
+                    Err(TwofaError::Io("Could not write encrypted storage"))
+                },
                 _ => {
-                    Err("Unhandled error")
+                    Err(TwofaError::Other("Could not encrypt storage"))
                 }
             }
         }
     }
 }
 
-pub fn decrypt_file(in_path: &str, out_path: &str, password: &str, logger: &Logger) -> Result<(), &'static str>{
+/// Decrypts with the vault key. A failed integrity check is reported as
+/// corruption if the key matches the check stored at `check_path`.
+pub fn decrypt_file(in_path: &str, out_path: &str, key: &VaultKey, check_path: Option<&str>, logger: &Logger) -> Result<(), TwofaError>{
     logger.min(
+        "decrypt",
         format!("Decrypt '{}' to '{}'", &in_path, &out_path)
             .as_str()
     );
@@ -37,20 +86,174 @@ pub fn decrypt_file(in_path: &str, out_path: &str, password: &str, logger: &Logg
     c.input_stream(ef::InputStream::File(in_path.to_owned()))
         .output_stream(ef::OutputStream::File(out_path.to_owned()))
         .add_output_option(ef::OutputOption::AllowOverwrite)
-        .password(ef::PasswordType::Text(password.to_owned(), ef::PasswordKeyGenMethod::ReadFromFile))
+        .password(key_password(key))
         .decrypt();
-    match ef::process(&c) {
+
+    match process_decrypt(&c) {
+        Err(TwofaError::WrongPassword) => {
+            match check_path.and_then(|path| matches_key_check(path, key)) {
+                Some(true) => {
+                    logger.min("decrypt_corrupt", "Key matches the stored check, content is damaged");
+                    Err(TwofaError::CorruptVault("content does not match its checksum"))
+                },
+                Some(false) => Err(TwofaError::WrongPassword),
+                None => {
+                    logger.min("decrypt_unchecked", "No key check stored, assuming wrong password");
+                    Err(TwofaError::WrongPassword)
+                },
+            }
+        },
+        result => result,
+    }
+}
+
+/// Storages written before key caching carry scrypt metadata in their header
+/// and can only be opened with the password itself.
+pub fn decrypt_legacy_file(in_path: &str, out_path: &str, password: &SecretString, logger: &Logger) -> Result<(), TwofaError>{
+    logger.min(
+        "decrypt_legacy",
+        format!("Decrypt legacy '{}' to '{}'", &in_path, &out_path)
+            .as_str()
+    );
+
+    let mut c = ef::Config::new();
+    c.input_stream(ef::InputStream::File(in_path.to_owned()))
+        .output_stream(ef::OutputStream::File(out_path.to_owned()))
+        .add_output_option(ef::OutputOption::AllowOverwrite)
+        .password(ef::PasswordType::Text(password.expose().to_owned(), ef::PasswordKeyGenMethod::ReadFromFile))
+        .decrypt();
+    process_decrypt(&c)
+}
+
+fn process_decrypt(c: &ef::Config) -> Result<(), TwofaError> {
+    match ef::process(c) {
         Ok(()) => Ok(()),
         Err(e) => {
             match e {
-                EncryptError::IoError(_error) => {
-                    Err("IoError")
+                EncryptError::IoError(error) => {
+                    if error.kind() == std::io::ErrorKind::NotFound {
+                        Err(TwofaError::VaultNotFound)
+                    } else {
+                        Err(TwofaError::Io("Could not read storage"))
+                    }
+                },
+                // a wrong key fails the hmac or, before that, the padding of the last block
+                EncryptError::HmacMismatch | EncryptError::CryptoError(_) => {
+                    Err(TwofaError::WrongPassword)
+                },
+                // legacy storages carry key metadata and need the password itself
+                EncryptError::UnexpectedEnumVariant(_) => {
+                    Err(TwofaError::WrongPassword)
+                },
+                EncryptError::UnexpectedVersion(found, _) => {
+                    Err(TwofaError::UnsupportedVersion(found))
+                },
+                EncryptError::ByteOrderError(_)
+                | EncryptError::HeaderTooSmall
+                | EncryptError::ShortIvRead
+                | EncryptError::ShortHmacRead => {
+                    Err(TwofaError::CorruptVault("file is truncated"))
+                },
+                EncryptError::BadHeaderMagic => {
+                    Err(TwofaError::CorruptVault("file is not a twofa storage"))
+                },
+                EncryptError::InvalidHmacLength
+                | EncryptError::IvIsZeroed
+                | EncryptError::InvalidKeyMetadataType(_)
+                | EncryptError::NoKeyMetadataFound(_) => {
+                    Err(TwofaError::CorruptVault("invalid header"))
                 },
                 _ => {
-                    Err("Unhandled error")
+                    Err(TwofaError::Other("Could not decrypt storage"))
                 }
             }
         }
     }
 }
 
+fn key_check(key: &VaultKey) -> String {
+    let check_key = hmac::Key::new(hmac::HMAC_SHA256, &key.expose()[..]);
+    hex::encode(hmac::sign(&check_key, KEY_CHECK_CONTEXT).as_ref())
+}
+
+/// Stores a value derived from the key, which tells a wrong password
+/// apart from a damaged storage without revealing the key.
+pub fn write_key_check(path: &str, key: &VaultKey) -> Result<(), TwofaError> {
+    match save_storage(path, &key_check(key)[..]) {
+        Ok(_) => Ok(()),
+        Err(_) => Err(TwofaError::Io("Could not write key check")),
+    }
+}
+
+/// Returns `None` if no key check has been stored yet.
+pub fn matches_key_check(path: &str, key: &VaultKey) -> Option<bool> {
+    if !check_storage(path) {
+        return None;
+    }
+
+    let stored = read_storage(path).ok()?;
+    let stored = hex::decode(stored.trim()).ok()?;
+    let check_key = hmac::Key::new(hmac::HMAC_SHA256, &key.expose()[..]);
+    Some(hmac::verify(&check_key, KEY_CHECK_CONTEXT, &stored[..]).is_ok())
+}
+
+/// Derives a sealing key for one purpose from the vault key, so data
+/// sealed for different purposes cannot be swapped.
+pub fn derive_subkey(key: &VaultKey, context: &[u8]) -> Zeroizing<Vec<u8>> {
+    let derive_key = hmac::Key::new(hmac::HMAC_SHA256, &key.expose()[..]);
+    Zeroizing::new(hmac::sign(&derive_key, context).as_ref().to_vec())
+}
+
+/// Short hash of a secret, to tell secrets apart without showing them.
+pub fn fingerprint(secret: &[u8]) -> String {
+    hex::encode(&digest::digest(&digest::SHA256, secret).as_ref()[..8])
+}
+
+pub fn random_bytes(len: usize) -> Result<Vec<u8>, &'static str> {
+    let mut bytes: Vec<u8> = vec![0; len];
+    match SystemRandom::new().fill(&mut bytes) {
+        Ok(()) => Ok(bytes),
+        Err(_) => Err("Could not generate random bytes"),
+    }
+}
+
+fn seal_key(key: &[u8]) -> Result<LessSafeKey, &'static str> {
+    match UnboundKey::new(&CHACHA20_POLY1305, key) {
+        Ok(k) => Ok(LessSafeKey::new(k)),
+        Err(_) => Err("Invalid sealing key"),
+    }
+}
+
+/// Encrypts `data` and returns nonce followed by ciphertext and tag.
+pub fn seal(key: &[u8], aad: &[u8], data: &[u8]) -> Result<Vec<u8>, &'static str> {
+    let key = seal_key(key)?;
+
+    let mut nonce_bytes = [0u8; NONCE_LEN];
+    nonce_bytes.copy_from_slice(&random_bytes(NONCE_LEN)?[..]);
+
+    let mut in_out = data.to_vec();
+    if key.seal_in_place_append_tag(Nonce::assume_unique_for_key(nonce_bytes), Aad::from(aad), &mut in_out).is_err() {
+        return Err("Could not seal data");
+    }
+
+    let mut sealed = nonce_bytes.to_vec();
+    sealed.append(&mut in_out);
+    Ok(sealed)
+}
+
+pub fn open(key: &[u8], aad: &[u8], sealed: &[u8]) -> Result<Zeroizing<Vec<u8>>, &'static str> {
+    let key = seal_key(key)?;
+
+    if sealed.len() < NONCE_LEN {
+        return Err("Sealed data too short");
+    }
+
+    let mut nonce_bytes = [0u8; NONCE_LEN];
+    nonce_bytes.copy_from_slice(&sealed[..NONCE_LEN]);
+
+    let mut in_out = Zeroizing::new(sealed[NONCE_LEN..].to_vec());
+    match key.open_in_place(Nonce::assume_unique_for_key(nonce_bytes), Aad::from(aad), &mut in_out) {
+        Ok(data) => Ok(Zeroizing::new(data.to_vec())),
+        Err(_) => Err("Could not open sealed data"),
+    }
+}
@@ -0,0 +1,202 @@
+use serde::Serialize;
+use serde_json::Value;
+use crate::crypto::fingerprint;
+use crate::error::TwofaError;
+use crate::secret::SecretString;
+use crate::vault::{Entry, Vault};
+
+// Compares the applications of two storages. Secrets, and fields this
+// version does not know, are only ever shown as fingerprints.
+
+#[derive(Serialize)]
+pub struct FieldChange {
+    pub field: String,
+    /// `null` if the field is not set
+    pub from: Value,
+    pub to: Value,
+}
+
+#[derive(Default, Serialize)]
+pub struct VaultDiff {
+    pub added: Vec<Value>,
+    pub removed: Vec<Value>,
+    pub modified: Vec<ModifiedEntry>,
+}
+
+#[derive(Serialize)]
+pub struct ModifiedEntry {
+    pub application: String,
+    pub changes: Vec<FieldChange>,
+}
+
+impl VaultDiff {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.modified.is_empty()
+    }
+}
+
+/// Settings of `entry` as compared, keyed by field name.
+fn fields(entry: &Entry) -> Vec<(String, Value)> {
+    let mut fields = vec![
+        (String::from("secret"), Value::from(fingerprint(entry.secret.expose().as_bytes()))),
+        (String::from("hash"), Value::from(entry.hash.name())),
+        (String::from("encoding"), Value::from(entry.encoding.name())),
+        (String::from("window"), Value::from(entry.window)),
+        (String::from("digits"), Value::from(entry.digits)),
+        (String::from("issuer"), entry.issuer.as_ref().map(|issuer| Value::from(&issuer[..])).unwrap_or(Value::Null)),
+    ];
+    for (field, value) in entry.extra.iter() {
+        fields.push((field.clone(), Value::from(fingerprint(value.to_string().as_bytes()))));
+    }
+    fields
+}
+
+/// The settings of an added or removed application.
+fn summary(app: &str, entry: &Entry) -> Value {
+    let mut summary = serde_json::Map::new();
+    summary.insert(String::from("application"), Value::from(app));
+    for (field, value) in fields(entry) {
+        if !value.is_null() {
+            summary.insert(field, value);
+        }
+    }
+    Value::Object(summary)
+}
+
+fn changes(from: &Entry, to: &Entry) -> Vec<FieldChange> {
+    let from = fields(from);
+    let to = fields(to);
+
+    let mut changes: Vec<FieldChange> = from.iter()
+        .map(|(field, old)| {
+            let new = to.iter().find(|(f, _)| f == field).map(|(_, v)| v.clone()).unwrap_or(Value::Null);
+            FieldChange { field: field.clone(), from: old.clone(), to: new }
+        })
+        .collect();
+    for (field, new) in to.iter().filter(|(field, _)| !from.iter().any(|(f, _)| f == field)) {
+        changes.push(FieldChange { field: field.clone(), from: Value::Null, to: new.clone() });
+    }
+
+    changes.retain(|change| change.from != change.to);
+    changes
+}
+
+/// What changed from `a` to `b`. When an application was last changed
+/// does not count as a difference.
+pub fn diff_vaults(a: &Vault, b: &Vault) -> VaultDiff {
+    let mut diff = VaultDiff::default();
+
+    for (app, entry) in a.entries.iter() {
+        match b.entries.get(app) {
+            None => diff.removed.push(summary(app, entry)),
+            Some(other) => {
+                let changes = changes(entry, other);
+                if !changes.is_empty() {
+                    diff.modified.push(ModifiedEntry { application: app.clone(), changes });
+                }
+            },
+        }
+    }
+    for (app, entry) in b.entries.iter().filter(|(app, _)| !a.entries.contains_key(*app)) {
+        diff.added.push(summary(app, entry));
+    }
+
+    diff
+}
+
+/// Reads the export at `path`, or opens it with `unlock` if it is encrypted.
+/// Nothing is printed while telling them apart, so `--format json` output
+/// stays parseable.
+pub fn open_copy(path: &str, unlock: impl FnOnce() -> Result<Vault, TwofaError>) -> Result<Vault, TwofaError> {
+    // encrypted storages are not valid UTF-8, let alone JSON
+    let data = std::fs::read(path).ok().and_then(|bytes| String::from_utf8(bytes).ok());
+    if let Some(data) = data {
+        let data = SecretString::new(data);
+        if data.expose().trim_start().starts_with('{') {
+            return Vault::from_json(data.expose());
+        }
+    }
+
+    unlock()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::{FileBackend, VaultBackend};
+    use crate::config::DefaultsConfig;
+    use crate::test_util::{key, logger, storage_dir};
+
+    fn vault(apps: &[(&str, &str, u32)]) -> Vault {
+        let mut vault = Vault::new();
+        for (app, secret, window) in apps {
+            let mut entry = Entry::new(SecretString::from(*secret), &DefaultsConfig::default());
+            entry.window = *window;
+            vault.insert_entry(app, entry, 1000);
+        }
+        vault
+    }
+
+    #[test]
+    fn lists_added_removed_and_modified_applications() {
+        let a = vault(&[("github", "JBSWY3DPEHPK3PXP", 30), ("gitlab", "GEZDGNBV", 30)]);
+        let b = vault(&[("github", "JBSWY3DPEHPK3PXP", 60), ("aws", "MFRGGZDF", 30)]);
+
+        let diff = diff_vaults(&a, &b);
+
+        assert_eq!(diff.added.len(), 1);
+        assert_eq!(diff.added[0]["application"], "aws");
+        assert_eq!(diff.removed[0]["application"], "gitlab");
+        assert_eq!(diff.modified.len(), 1);
+        let changes = &diff.modified[0].changes;
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].field, "window");
+        assert_eq!((&changes[0].from, &changes[0].to), (&Value::from(30), &Value::from(60)));
+    }
+
+    #[test]
+    fn shows_secrets_only_as_fingerprints() {
+        let a = vault(&[("github", "JBSWY3DPEHPK3PXP", 30)]);
+        let b = vault(&[("github", "GEZDGNBV", 30), ("aws", "MFRGGZDF", 30)]);
+
+        let diff = diff_vaults(&a, &b);
+        let json = serde_json::to_string(&diff).unwrap();
+
+        assert_eq!(diff.modified[0].changes[0].field, "secret");
+        assert_eq!(diff.modified[0].changes[0].to, Value::from(fingerprint(b"GEZDGNBV")));
+        for secret in ["JBSWY3DPEHPK3PXP", "GEZDGNBV", "MFRGGZDF"] {
+            assert!(!json.contains(secret));
+        }
+    }
+
+    #[test]
+    fn ignores_when_applications_were_changed() {
+        let a = vault(&[("github", "JBSWY3DPEHPK3PXP", 30)]);
+        let mut b = vault(&[("github", "JBSWY3DPEHPK3PXP", 30)]);
+        b.entries.get_mut("github").unwrap().modified = 2000;
+
+        assert!(diff_vaults(&a, &b).is_empty());
+    }
+
+    #[test]
+    fn diffs_encrypted_storages_as_json() {
+        let storage_path = storage_dir("diff-json");
+        let logger = logger();
+        let key = key(7);
+
+        let backend = FileBackend::new(&storage_path.en_file[..], &storage_path);
+        backend.save(&key, &vault(&[("github", "JBSWY3DPEHPK3PXP", 30), ("aws", "MFRGGZDF", 30)]), &logger).unwrap();
+        let export = format!("{}/export.json", &storage_path.dir);
+        std::fs::write(&export, vault(&[("github", "JBSWY3DPEHPK3PXP", 60)]).to_json().unwrap().expose()).unwrap();
+
+        let a = open_copy(&storage_path.en_file[..], || backend.load(&key, &logger)).unwrap();
+        let b = open_copy(&export[..], || panic!("exports need no key")).unwrap();
+        let json: Value = serde_json::from_str(&serde_json::to_string(&diff_vaults(&a, &b)).unwrap()).unwrap();
+
+        assert_eq!(json["removed"][0]["application"], "aws");
+        assert_eq!(json["modified"][0]["application"], "github");
+        assert_eq!(json["modified"][0]["changes"][0]["field"], "window");
+
+        std::fs::remove_dir_all(&storage_path.dir).unwrap();
+    }
+}
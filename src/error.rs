@@ -0,0 +1,68 @@
+use std::fmt;
+
+/// Error returned by all commands. Every kind exits with its own code,
+/// documented in the README, so scripts can tell them apart.
+#[derive(Debug)]
+pub enum TwofaError {
+    WrongPassword,
+    VaultNotFound,
+    UnsupportedVersion(u32),
+    CorruptVault(&'static str),
+    EntryNotFound(String),
+    BackupNotFound(String),
+    RevisionNotFound(String),
+    ProfileNotFound(String),
+    InvalidSecret(&'static str),
+    Io(&'static str),
+    Aborted,
+    Locked(Option<u32>),
+    InsecurePermissions(String),
+    Other(&'static str),
+}
+
+impl TwofaError {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            TwofaError::Other(_) => 1,
+            // 2 is used by clap for invalid arguments
+            TwofaError::WrongPassword => 3,
+            TwofaError::CorruptVault(_) => 4,
+            TwofaError::EntryNotFound(_) | TwofaError::BackupNotFound(_) | TwofaError::RevisionNotFound(_) | TwofaError::ProfileNotFound(_) => 5,
+            TwofaError::InvalidSecret(_) => 6,
+            TwofaError::Io(_) => 7,
+            TwofaError::Aborted => 8,
+            TwofaError::VaultNotFound => 9,
+            TwofaError::UnsupportedVersion(_) => 10,
+            TwofaError::Locked(_) => 11,
+            TwofaError::InsecurePermissions(_) => 12,
+        }
+    }
+}
+
+impl fmt::Display for TwofaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TwofaError::WrongPassword => write!(f, "Wrong password"),
+            TwofaError::VaultNotFound => write!(f, "Storage does not exist. Create it with 'twofa init'"),
+            TwofaError::UnsupportedVersion(version) => write!(f, "Storage format version {} is not supported. Update twofa to open it", version),
+            TwofaError::CorruptVault(msg) => write!(f, "Storage is corrupt: {}. Restore it from a backup or export", msg),
+            TwofaError::EntryNotFound(app) => write!(f, "Application '{}' does not exist", app),
+            TwofaError::BackupNotFound(id) => write!(f, "Backup '{}' does not exist. See 'twofa backup list'", id),
+            TwofaError::RevisionNotFound(rev) => write!(f, "Revision '{}' does not exist. See 'twofa history'", rev),
+            TwofaError::ProfileNotFound(name) => write!(f, "Profile '{}' does not exist. Create it with 'twofa profile create {}'", name, name),
+            TwofaError::InvalidSecret(msg) => write!(f, "Invalid secret: {}", msg),
+            TwofaError::Io(msg) => write!(f, "{}", msg),
+            TwofaError::Aborted => write!(f, "Stopping action"),
+            TwofaError::Locked(Some(pid)) => write!(f, "Storage is locked by PID {}. Try again once it has finished", pid),
+            TwofaError::Locked(None) => write!(f, "Storage is locked by another process. Try again once it has finished"),
+            TwofaError::InsecurePermissions(path) => write!(f, "'{}' is accessible by other users or not owned by you. Run 'twofa doctor --fix'", path),
+            TwofaError::Other(msg) => write!(f, "{}", msg),
+        }
+    }
+}
+
+impl From<&'static str> for TwofaError {
+    fn from(msg: &'static str) -> Self {
+        TwofaError::Other(msg)
+    }
+}
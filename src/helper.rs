@@ -1,13 +1,18 @@
 use std::io::{stdin,stdout,Write};
 use serde_json::{Value};
-use std::fs::{create_dir_all};
+use zeroize::Zeroize;
+use crate::secret::SecretString;
+use crate::error::TwofaError;
+use crate::storage::create_private_dir;
 use std::path::{Path};
 
-pub fn prompt_for_input(input: &'static str) -> Result<String, &'static str> {
+pub fn prompt_for_input(input: &str) -> Result<String, TwofaError> {
     let mut s=String::new();
     print!("{}: ", input);
     let _=stdout().flush();
-    stdin().read_line(&mut s).expect("Did not enter a correct string");
+    if stdin().read_line(&mut s).is_err() {
+        return Err(TwofaError::Io("Could not read input"));
+    }
     if let Some('\n')=s.chars().next_back() {
         s.pop();
     }
@@ -17,44 +22,48 @@ pub fn prompt_for_input(input: &'static str) -> Result<String, &'static str> {
     Ok(s)
 }
 
-pub fn merge_json(a: &mut Value, b: Value) {
-    if let Value::Object(a) = a {
-        if let Value::Object(b) = b {
-            for (k, v) in b {
-                if v.is_null() {
-                    a.remove(&k);
-                }
-                else {
-                    merge_json(a.entry(k).or_insert(Value::Null), v);
-                }
-            }
-
-            return;
-        }
+pub fn prompt_for_password(input: &'static str) -> Result<SecretString, TwofaError> {
+    match rpassword::read_password_from_tty(Some(format!("{}: ", input).as_str())) {
+        Ok(password) => Ok(SecretString::new(password)),
+        Err(_) => Err(TwofaError::Io("Could not read password")),
     }
+}
 
-    *a = b;
+/// Overwrites every string in `value` before it is dropped.
+pub fn wipe_json(value: &mut Value) {
+    match value {
+        Value::String(s) => s.zeroize(),
+        Value::Array(items) => {
+            for item in items.iter_mut() {
+                wipe_json(item);
+            }
+        },
+        Value::Object(map) => {
+            for (_, item) in map.iter_mut() {
+                wipe_json(item);
+            }
+        },
+        _ => {}
+    }
 }
 
-pub fn create_folder(path: &str) -> () {
+pub fn create_folder(path: &str) -> Result<(), TwofaError> {
     let file_or_dir = Path::new(path);
-    return if file_or_dir.exists() {
+    if file_or_dir.exists() {
         if file_or_dir.is_file() {
-            println!("Dir to create is already a file. Aborting");
-            std::process::exit(1);
+            return Err(TwofaError::Io("Dir to create is already a file"));
         };
 
         if file_or_dir.is_dir() {
             println!("Directory already exists.");
         }
 
-        ()
+        Ok(())
     } else {
-        if let Err(_) = create_dir_all(path) {
-            println!("Could not create directories");
-            std::process::exit(1);
+        if create_private_dir(path).is_err() {
+            return Err(TwofaError::Io("Could not create directories"));
         }
 
-        ()
-    };
+        Ok(())
+    }
 }
\ No newline at end of file
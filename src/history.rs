@@ -0,0 +1,153 @@
+use std::path::Path;
+use std::process::{Command, Output};
+use crate::error::TwofaError;
+use crate::logger::Logger;
+use crate::storage::{check_storage, save_storage, Storage};
+
+/// Only the encrypted storage and its key check are committed, never
+/// buffers, sessions, backups or the config.
+const GITIGNORE: &str = "*\n!.gitignore\n!twofa.storage\n!twofa.db\n!twofa.check\n";
+const STORAGE_FILES: &[&str] = &["twofa.db", "twofa.storage"];
+
+pub struct Revision {
+    pub id: String,
+    /// seconds since epoch
+    pub created: u64,
+    pub message: String,
+}
+
+fn git(dir: &str, args: &[&str], logger: &Logger) -> Result<Output, TwofaError> {
+    logger.mid(
+        "git",
+        format!("git {}", args.join(" "))
+            .as_str()
+    );
+
+    // commits are made by twofa in the storage directory, whatever the
+    // user's git config and environment say
+    let output = Command::new("git")
+        .env_remove("GIT_DIR")
+        .env_remove("GIT_WORK_TREE")
+        .env_remove("GIT_INDEX_FILE")
+        .arg("-C").arg(dir)
+        .args(["-c", "user.name=twofa", "-c", "user.email=twofa@localhost", "-c", "commit.gpgsign=false"])
+        .args(args)
+        .output();
+
+    match output {
+        Ok(output) => {
+            logger.max("git_status", format!("git {}", output.status).as_str());
+            Ok(output)
+        },
+        Err(_) => Err(TwofaError::Other("Could not run git, is it installed?")),
+    }
+}
+
+fn git_ok(dir: &str, args: &[&str], logger: &Logger) -> Result<Vec<u8>, TwofaError> {
+    let output = git(dir, args, logger)?;
+    if !output.status.success() {
+        logger.min("git_failed", String::from_utf8_lossy(&output.stderr).trim());
+        return Err(TwofaError::Other("git failed, see the log with -d for details"));
+    }
+
+    Ok(output.stdout)
+}
+
+pub fn has_history(storage_path: &Storage) -> bool {
+    Path::new(&storage_path.dir).join(".git").exists()
+}
+
+fn init_history(storage_path: &Storage, logger: &Logger) -> Result<(), TwofaError> {
+    git_ok(&storage_path.dir[..], &["init", "-q"], logger)?;
+
+    let gitignore = format!("{}/.gitignore", &storage_path.dir);
+    if save_storage(&gitignore[..], GITIGNORE).is_err() {
+        return Err(TwofaError::Io("Could not write .gitignore"));
+    }
+
+    logger.norm(
+        "history_created",
+        format!("Keeping the history of the storage in '{}/.git'", &storage_path.dir)
+            .as_str()
+    );
+    Ok(())
+}
+
+/// Commits the current storage files with `message`, creating the
+/// repository first if needed. Does nothing if the files did not change.
+pub fn record_change(storage_path: &Storage, message: &str, logger: &Logger) -> Result<(), TwofaError> {
+    let dir = &storage_path.dir[..];
+    if !has_history(storage_path) {
+        init_history(storage_path, logger)?;
+    }
+
+    git_ok(dir, &["add", "-A", "--", "."], logger)?;
+    // exit code 0 means nothing is staged
+    if git(dir, &["diff", "--cached", "--quiet"], logger)?.status.success() {
+        return Ok(());
+    }
+    git_ok(dir, &["commit", "-q", "-m", message], logger)?;
+
+    logger.min(
+        "history_recorded",
+        format!("Recorded '{}'", message)
+            .as_str()
+    );
+    Ok(())
+}
+
+/// Revisions of the storage, newest first.
+pub fn list_history(storage_path: &Storage, logger: &Logger) -> Result<Vec<Revision>, TwofaError> {
+    if !has_history(storage_path) {
+        return Ok(Vec::new());
+    }
+
+    // a repository without commits has no log
+    let output = git(&storage_path.dir[..], &["log", "--format=%h%x09%ct%x09%s"], logger)?;
+    if !output.status.success() {
+        return Ok(Vec::new());
+    }
+
+    let log = String::from_utf8_lossy(&output.stdout).into_owned();
+    let revisions = log.lines()
+        .filter_map(|line| {
+            let mut fields = line.splitn(3, '\t');
+            Some(Revision {
+                id: fields.next()?.to_owned(),
+                created: fields.next()?.parse().ok()?,
+                message: fields.next().unwrap_or("").to_owned(),
+            })
+        })
+        .collect();
+
+    Ok(revisions)
+}
+
+/// Writes the storage file as of revision `rev` to `out`.
+pub fn checkout_revision(storage_path: &Storage, rev: &str, out: &str, logger: &Logger) -> Result<(), TwofaError> {
+    let dir = &storage_path.dir[..];
+    // anything git would read as an option is not a revision
+    if !has_history(storage_path) || rev.is_empty() || rev.starts_with('-') {
+        return Err(TwofaError::RevisionNotFound(rev.to_owned()));
+    }
+
+    let commit = format!("{}^{{commit}}", rev);
+    if !git(dir, &["rev-parse", "--quiet", "--verify", &commit[..]], logger)?.status.success() {
+        return Err(TwofaError::RevisionNotFound(rev.to_owned()));
+    }
+
+    for file in STORAGE_FILES {
+        let object = format!("{}:{}", rev, file);
+        let output = git(dir, &["show", &object[..]], logger)?;
+        if !output.status.success() {
+            continue;
+        }
+
+        if std::fs::write(out, &output.stdout).is_err() || !check_storage(out) {
+            return Err(TwofaError::Io("Could not write storage of the revision"));
+        }
+        return Ok(());
+    }
+
+    Err(TwofaError::RevisionNotFound(rev.to_owned()))
+}
@@ -0,0 +1,182 @@
+use zeroize::Zeroizing;
+use crate::logger::Logger;
+
+pub enum Keyring {
+    Session,
+    User,
+}
+
+impl Keyring {
+    pub fn from_name(name: &str) -> Result<Self, &'static str> {
+        match name {
+            "session" => Ok(Keyring::Session),
+            "user" => Ok(Keyring::User),
+            _ => Err("Unknown keyring. Use 'session' or 'user'"),
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod sys {
+    use std::ffi::CString;
+    use zeroize::Zeroizing;
+    use super::Keyring;
+
+    const KEY_TYPE: &str = "user";
+
+    fn keyring_id(keyring: &Keyring) -> libc::c_long {
+        match keyring {
+            Keyring::Session => libc::KEY_SPEC_SESSION_KEYRING as libc::c_long,
+            Keyring::User => libc::KEY_SPEC_USER_KEYRING as libc::c_long,
+        }
+    }
+
+    fn search(description: &str, keyring: &Keyring) -> Option<libc::c_long> {
+        let key_type = CString::new(KEY_TYPE).ok()?;
+        let description = CString::new(description).ok()?;
+
+        let serial = unsafe {
+            libc::syscall(
+                libc::SYS_keyctl,
+                libc::KEYCTL_SEARCH as libc::c_long,
+                keyring_id(keyring),
+                key_type.as_ptr(),
+                description.as_ptr(),
+                0 as libc::c_long,
+            )
+        };
+
+        if serial < 0 {
+            None
+        } else {
+            Some(serial)
+        }
+    }
+
+    pub fn add(description: &str, payload: &[u8], timeout: u32, keyring: &Keyring) -> Result<(), &'static str> {
+        let key_type = CString::new(KEY_TYPE).map_err(|_| "Invalid key type")?;
+        let c_description = CString::new(description).map_err(|_| "Invalid key description")?;
+
+        let serial = unsafe {
+            libc::syscall(
+                libc::SYS_add_key,
+                key_type.as_ptr(),
+                c_description.as_ptr(),
+                payload.as_ptr(),
+                payload.len(),
+                keyring_id(keyring),
+            )
+        };
+        if serial < 0 {
+            return Err("Could not add key to kernel keyring");
+        }
+
+        let res = unsafe {
+            libc::syscall(
+                libc::SYS_keyctl,
+                libc::KEYCTL_SET_TIMEOUT as libc::c_long,
+                serial,
+                timeout as libc::c_long,
+            )
+        };
+        if res < 0 {
+            revoke(description, keyring);
+            return Err("Could not set timeout on cached key");
+        }
+
+        Ok(())
+    }
+
+    pub fn read(description: &str, keyring: &Keyring, len: usize) -> Option<Zeroizing<Vec<u8>>> {
+        let serial = search(description, keyring)?;
+        let mut buffer = Zeroizing::new(vec![0u8; len]);
+
+        let read = unsafe {
+            libc::syscall(
+                libc::SYS_keyctl,
+                libc::KEYCTL_READ as libc::c_long,
+                serial,
+                buffer.as_mut_ptr(),
+                buffer.len(),
+            )
+        };
+
+        if read as usize != len {
+            return None;
+        }
+
+        Some(buffer)
+    }
+
+    pub fn revoke(description: &str, keyring: &Keyring) -> bool {
+        let serial = match search(description, keyring) {
+            Some(s) => s,
+            None => return false,
+        };
+
+        unsafe {
+            libc::syscall(libc::SYS_keyctl, libc::KEYCTL_REVOKE as libc::c_long, serial);
+            libc::syscall(libc::SYS_keyctl, libc::KEYCTL_UNLINK as libc::c_long, serial, keyring_id(keyring));
+        }
+
+        true
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod sys {
+    use zeroize::Zeroizing;
+    use super::Keyring;
+
+    pub fn add(_description: &str, _payload: &[u8], _timeout: u32, _keyring: &Keyring) -> Result<(), &'static str> {
+        Err("Kernel keyring is only available on Linux")
+    }
+
+    pub fn read(_description: &str, _keyring: &Keyring, _len: usize) -> Option<Zeroizing<Vec<u8>>> {
+        None
+    }
+
+    pub fn revoke(_description: &str, _keyring: &Keyring) -> bool {
+        false
+    }
+}
+
+pub fn store_key(description: &str, key: &[u8], timeout: u32, keyring: &Keyring, logger: &Logger) -> Result<(), &'static str> {
+    sys::add(description, key, timeout, keyring)?;
+
+    logger.min(
+        "key_cached",
+        format!("Cached key '{}' for {} seconds", &description, &timeout)
+            .as_str()
+    );
+
+    Ok(())
+}
+
+pub fn read_key(description: &str, keyring: &Keyring, len: usize, logger: &Logger) -> Option<Zeroizing<Vec<u8>>> {
+    let key = sys::read(description, keyring, len);
+
+    if key.is_some() {
+        logger.min(
+            "key_found",
+            format!("Found cached key '{}'", &description)
+                .as_str()
+        );
+    }
+
+    key
+}
+
+pub fn revoke_key(description: &str, keyring: &Keyring, logger: &Logger) -> bool {
+    let revoked = sys::revoke(description, keyring);
+
+    if revoked {
+        logger.min(
+            "key_revoked",
+            format!("Revoked cached key '{}'", &description)
+                .as_str()
+        );
+    }
+
+    revoked
+}
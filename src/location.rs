@@ -0,0 +1,227 @@
+use std::fs::rename;
+use std::path::{Path, PathBuf};
+use crate::error::TwofaError;
+use crate::lock::{try_acquire_lock, LockMode};
+use crate::profile::{profile_dir, validate_profile_name, PROFILE_VAR};
+use crate::storage::create_private_dir;
+
+pub const HOME_VAR: &str = "TWOFA_HOME";
+pub const VAULT_VAR: &str = "TWOFA_VAULT";
+/// a directory with this name next to the binary enables portable mode
+pub const PORTABLE_DIR: &str = "twofa-data";
+const LEGACY_DIR: &str = ".twofa";
+
+fn env_path(name: &str) -> Option<String> {
+    match std::env::var(name) {
+        Ok(value) if !value.is_empty() => Some(value),
+        _ => None,
+    }
+}
+
+/// Makes relative paths absolute, the storage path also names its cached
+/// key and session.
+pub fn absolute_path(path: &str) -> Result<String, TwofaError> {
+    let path = Path::new(path);
+    if path.is_absolute() {
+        return Ok(path.to_string_lossy().into_owned());
+    }
+
+    match std::env::current_dir() {
+        Ok(dir) => Ok(dir.join(path).to_string_lossy().into_owned()),
+        Err(_) => Err(TwofaError::Io("Could not resolve relative path")),
+    }
+}
+
+fn portable_home(exe_dir: &Path) -> Option<String> {
+    let dir = exe_dir.join(PORTABLE_DIR);
+    if dir.is_dir() {
+        Some(dir.to_string_lossy().into_owned())
+    } else {
+        None
+    }
+}
+
+/// `$XDG_DATA_HOME/twofa`, by default `~/.local/share/twofa`.
+fn data_home(var: &dyn Fn(&str) -> Option<String>) -> Result<String, TwofaError> {
+    if let Some(data) = var("XDG_DATA_HOME") {
+        // the spec says to ignore relative paths
+        if Path::new(&data).is_absolute() {
+            return Ok(PathBuf::from(data).join("twofa").to_string_lossy().into_owned());
+        }
+    }
+
+    match var("HOME") {
+        Some(home) => Ok(PathBuf::from(home).join(".local/share/twofa").to_string_lossy().into_owned()),
+        None => Err(TwofaError::Other("Could not find the storage, set HOME, XDG_DATA_HOME or TWOFA_HOME")),
+    }
+}
+
+fn legacy_home(var: &dyn Fn(&str) -> Option<String>) -> Option<String> {
+    let dir = PathBuf::from(var("HOME")?).join(LEGACY_DIR);
+    if dir.is_dir() {
+        Some(dir.to_string_lossy().into_owned())
+    } else {
+        None
+    }
+}
+
+/// Moves `~/.twofa` of earlier versions to the data directory. Stays with
+/// the old directory if another twofa is using it or it cannot be moved.
+fn migrate_legacy_home(legacy: &str, home: &str) -> String {
+    let lock = match try_acquire_lock(&format!("{}/twofa.lock", legacy)[..], LockMode::Exclusive) {
+        Ok(Some(lock)) => lock,
+        _ => return legacy.to_owned(),
+    };
+
+    let moved = match Path::new(home).parent() {
+        Some(parent) => create_private_dir(&parent.to_string_lossy()[..]).is_ok() && rename(legacy, home).is_ok(),
+        None => false,
+    };
+    drop(lock);
+
+    if moved {
+        eprintln!("Moved '{}' to '{}'", legacy, home);
+        home.to_owned()
+    } else {
+        eprintln!("Could not move '{}' to '{}', still using it", legacy, home);
+        legacy.to_owned()
+    }
+}
+
+/// Directory of the config, logs and, unless given elsewhere, the storage:
+/// `TWOFA_HOME`, the portable directory next to the binary or the data
+/// directory, in that order.
+pub fn find_home() -> Result<String, TwofaError> {
+    let exe = std::env::current_exe().ok();
+    resolve_home(&env_path, exe.as_ref().and_then(|exe| exe.parent()))
+}
+
+/// `find_home` with the environment variables read through `var` and the
+/// directory of the binary given.
+fn resolve_home(var: &dyn Fn(&str) -> Option<String>, exe_dir: Option<&Path>) -> Result<String, TwofaError> {
+    if let Some(home) = var(HOME_VAR) {
+        return absolute_path(&home[..]);
+    }
+
+    if let Some(home) = exe_dir.and_then(portable_home) {
+        return Ok(home);
+    }
+
+    let home = data_home(var)?;
+    match legacy_home(var) {
+        Some(legacy) if !Path::new(&home).exists() => Ok(migrate_legacy_home(&legacy[..], &home[..])),
+        _ => Ok(home),
+    }
+}
+
+fn profile_vault(home: &str, name: &str) -> Result<(String, Option<String>), TwofaError> {
+    validate_profile_name(name)?;
+    Ok((profile_dir(home, name), Some(name.to_owned())))
+}
+
+/// Directory of the storage, its backups and lock together with the profile
+/// it belongs to: `--vault`, `--profile`, `TWOFA_VAULT`, `TWOFA_PROFILE`,
+/// the configured default profile or the home directory, in that order.
+pub fn find_vault_dir(home: &str, vault: Option<&str>, profile: Option<&str>, default_profile: &str) -> Result<(String, Option<String>), TwofaError> {
+    resolve_vault_dir(&env_path, home, vault, profile, default_profile)
+}
+
+fn resolve_vault_dir(var: &dyn Fn(&str) -> Option<String>, home: &str, vault: Option<&str>, profile: Option<&str>, default_profile: &str) -> Result<(String, Option<String>), TwofaError> {
+    if let Some(dir) = vault {
+        return Ok((absolute_path(dir)?, None));
+    }
+    if let Some(name) = profile {
+        return profile_vault(home, name);
+    }
+    if let Some(dir) = var(VAULT_VAR) {
+        return Ok((absolute_path(&dir[..])?, None));
+    }
+
+    match var(PROFILE_VAR) {
+        Some(name) => profile_vault(home, &name[..]),
+        None => profile_vault(home, default_profile),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::fs::{create_dir_all, read_to_string, remove_dir_all, write};
+    use crate::test_util::temp_dir;
+
+    fn env(vars: &[(&str, &str)]) -> impl Fn(&str) -> Option<String> {
+        let vars: HashMap<String, String> = vars.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+        move |name| vars.get(name).cloned()
+    }
+
+    #[test]
+    fn prefers_twofa_home_then_the_portable_dir_then_xdg() {
+        let dir = temp_dir("location-order");
+        let exe_dir = Path::new(&dir).join("bin");
+        create_dir_all(exe_dir.join(PORTABLE_DIR)).unwrap();
+        let portable = exe_dir.join(PORTABLE_DIR).to_string_lossy().into_owned();
+
+        let all = env(&[(HOME_VAR, "/twofa-home"), ("XDG_DATA_HOME", "/data"), ("HOME", "/home/u")]);
+        assert_eq!(resolve_home(&all, Some(&exe_dir)).unwrap(), "/twofa-home");
+
+        let no_twofa_home = env(&[("XDG_DATA_HOME", "/data"), ("HOME", "/home/u")]);
+        assert_eq!(resolve_home(&no_twofa_home, Some(&exe_dir)).unwrap(), portable);
+        assert_eq!(resolve_home(&no_twofa_home, Some(Path::new(&dir))).unwrap(), "/data/twofa");
+
+        let relative_xdg = env(&[("XDG_DATA_HOME", "data"), ("HOME", "/home/u")]);
+        assert_eq!(resolve_home(&relative_xdg, None).unwrap(), "/home/u/.local/share/twofa");
+        assert!(resolve_home(&env(&[]), None).is_err());
+
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn prefers_vault_then_profile_then_env_then_the_default_profile() {
+        let dir = |vars: &[(&str, &str)], vault, profile| resolve_vault_dir(&env(vars), "/home", vault, profile, "fallback").unwrap();
+        let both = [(VAULT_VAR, "/env-vault"), (PROFILE_VAR, "env")];
+
+        assert_eq!(dir(&both, Some("/vault"), None), ("/vault".to_owned(), None));
+        assert_eq!(dir(&both, None, Some("work")), ("/home/profiles/work".to_owned(), Some("work".to_owned())));
+        assert_eq!(dir(&both, None, None), ("/env-vault".to_owned(), None));
+        assert_eq!(dir(&[(PROFILE_VAR, "env")], None, None), ("/home/profiles/env".to_owned(), Some("env".to_owned())));
+        assert_eq!(dir(&[], None, None), ("/home/profiles/fallback".to_owned(), Some("fallback".to_owned())));
+        assert_eq!(dir(&[(PROFILE_VAR, "default")], None, None), ("/home".to_owned(), Some("default".to_owned())));
+
+        assert!(resolve_vault_dir(&env(&[(PROFILE_VAR, "..")]), "/home", None, None, "default").is_err());
+    }
+
+    #[test]
+    fn moves_the_legacy_dir_once() {
+        let dir = temp_dir("location-legacy");
+        create_dir_all(format!("{}/{}", dir, LEGACY_DIR)).unwrap();
+        write(format!("{}/{}/twofa.storage", dir, LEGACY_DIR), "encrypted").unwrap();
+        let vars = env(&[("HOME", &dir[..])]);
+        let home = format!("{}/.local/share/twofa", dir);
+
+        assert_eq!(resolve_home(&vars, None).unwrap(), home);
+        assert!(!Path::new(&format!("{}/{}", dir, LEGACY_DIR)).exists());
+        assert_eq!(read_to_string(format!("{}/twofa.storage", home)).unwrap(), "encrypted");
+
+        // a legacy dir showing up again is left alone once the new one exists
+        create_dir_all(format!("{}/{}", dir, LEGACY_DIR)).unwrap();
+        assert_eq!(resolve_home(&vars, None).unwrap(), home);
+        assert!(Path::new(&format!("{}/{}", dir, LEGACY_DIR)).exists());
+
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn keeps_the_legacy_dir_while_it_is_locked() {
+        let dir = temp_dir("location-locked");
+        let legacy = format!("{}/{}", dir, LEGACY_DIR);
+        create_dir_all(&legacy).unwrap();
+        let lock = try_acquire_lock(&format!("{}/twofa.lock", legacy)[..], LockMode::Exclusive).unwrap().unwrap();
+
+        assert_eq!(resolve_home(&env(&[("HOME", &dir[..])]), None).unwrap(), legacy);
+        assert!(!Path::new(&format!("{}/.local/share/twofa", dir)).exists());
+
+        drop(lock);
+        remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,203 @@
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::time::{Duration, Instant};
+use crate::error::TwofaError;
+use crate::logger::Logger;
+
+const RETRY_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum LockMode {
+    /// several processes may read the storage at once
+    Shared,
+    /// needed to write the storage
+    Exclusive,
+}
+
+/// Advisory lock on the storage, released when dropped.
+///
+/// The lock is held on a separate file because the storage itself is
+/// replaced by renaming a new file over it.
+pub struct VaultLock {
+    file: File,
+    mode: LockMode,
+}
+
+impl VaultLock {
+    pub fn is_exclusive(&self) -> bool {
+        self.mode == LockMode::Exclusive
+    }
+}
+
+impl Drop for VaultLock {
+    fn drop(&mut self) {
+        sys::unlock(&self.file);
+    }
+}
+
+#[cfg(unix)]
+mod sys {
+    use std::fs::File;
+    use std::os::unix::io::AsRawFd;
+    use super::LockMode;
+
+    /// Returns false if another process holds a conflicting lock.
+    pub fn try_lock(file: &File, mode: LockMode) -> Result<bool, &'static str> {
+        let operation = match mode {
+            LockMode::Shared => libc::LOCK_SH,
+            LockMode::Exclusive => libc::LOCK_EX,
+        };
+
+        let res = unsafe { libc::flock(file.as_raw_fd(), operation | libc::LOCK_NB) };
+        if res == 0 {
+            return Ok(true);
+        }
+
+        match std::io::Error::last_os_error().raw_os_error() {
+            Some(libc::EWOULDBLOCK) => Ok(false),
+            Some(libc::EINTR) => Ok(false),
+            _ => Err("Could not lock storage"),
+        }
+    }
+
+    pub fn unlock(file: &File) {
+        unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_UN) };
+    }
+}
+
+#[cfg(not(unix))]
+mod sys {
+    use std::fs::File;
+    use super::LockMode;
+
+    pub fn try_lock(_file: &File, _mode: LockMode) -> Result<bool, &'static str> {
+        Ok(true)
+    }
+
+    pub fn unlock(_file: &File) {}
+}
+
+fn read_holder(file: &mut File) -> Option<u32> {
+    let mut content = String::new();
+    file.seek(SeekFrom::Start(0)).ok()?;
+    file.read_to_string(&mut content).ok()?;
+    content.trim().parse().ok()
+}
+
+fn write_holder(file: &mut File) {
+    // only a hint for the error message of waiting processes
+    let _ = file.set_len(0)
+        .and_then(|_| file.seek(SeekFrom::Start(0)))
+        .and_then(|_| file.write_all(std::process::id().to_string().as_bytes()));
+}
+
+fn open_lock_file(path: &str) -> Result<File, TwofaError> {
+    let mut options = OpenOptions::new();
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(crate::storage::PRIVATE_FILE_MODE);
+    }
+
+    match options.read(true).write(true).create(true).truncate(false).open(path) {
+        Ok(f) => Ok(f),
+        Err(_) => Err(TwofaError::Io("Could not open lock file")),
+    }
+}
+
+/// Locks the storage only if no other process uses it right now.
+pub fn try_acquire_lock(path: &str, mode: LockMode) -> Result<Option<VaultLock>, TwofaError> {
+    let mut file = open_lock_file(path)?;
+    if !sys::try_lock(&file, mode)? {
+        return Ok(None);
+    }
+
+    write_holder(&mut file);
+    Ok(Some(VaultLock {
+        file,
+        mode,
+    }))
+}
+
+/// Locks the storage, waiting up to `timeout` seconds for other
+/// twofa processes to finish.
+pub fn acquire_lock(path: &str, mode: LockMode, timeout: u64, logger: &Logger) -> Result<VaultLock, TwofaError> {
+    let mut file = open_lock_file(path)?;
+
+    let deadline = Instant::now() + Duration::from_secs(timeout);
+    let mut waiting = false;
+
+    loop {
+        if sys::try_lock(&file, mode)? {
+            break;
+        }
+
+        if Instant::now() >= deadline {
+            return Err(TwofaError::Locked(read_holder(&mut file)));
+        }
+
+        if !waiting {
+            waiting = true;
+            let holder = match read_holder(&mut file) {
+                Some(pid) => format!("PID {}", pid),
+                None => String::from("another process"),
+            };
+            logger.norm(
+                "lock_wait",
+                format!("Storage is locked by {}, waiting up to {} seconds", holder, timeout)
+                    .as_str()
+            );
+        }
+
+        std::thread::sleep(RETRY_INTERVAL);
+    }
+
+    write_holder(&mut file);
+
+    logger.min(
+        "lock_acquired",
+        format!("Acquired {} lock on '{}'", if mode == LockMode::Exclusive { "exclusive" } else { "shared" }, &path)
+            .as_str()
+    );
+
+    Ok(VaultLock {
+        file,
+        mode,
+    })
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::test_util::{logger, temp_dir};
+
+    #[test]
+    fn times_out_on_an_exclusive_lock_naming_its_holder() {
+        let dir = temp_dir("lock-timeout");
+        let path = format!("{}/twofa.lock", &dir);
+        let _held = acquire_lock(&path, LockMode::Exclusive, 0, &logger()).unwrap();
+
+        let start = Instant::now();
+        let err = acquire_lock(&path, LockMode::Shared, 1, &logger()).err().unwrap();
+
+        assert!(start.elapsed() >= Duration::from_secs(1));
+        assert_eq!(err.exit_code(), 11);
+        assert!(matches!(err, TwofaError::Locked(Some(pid)) if pid == std::process::id()));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn shares_the_lock_between_readers_only() {
+        let dir = temp_dir("lock-shared");
+        let path = format!("{}/twofa.lock", &dir);
+
+        let first = acquire_lock(&path, LockMode::Shared, 0, &logger()).unwrap();
+        let second = acquire_lock(&path, LockMode::Shared, 0, &logger()).unwrap();
+        assert!(try_acquire_lock(&path, LockMode::Exclusive).unwrap().is_none());
+
+        drop(first);
+        drop(second);
+        assert!(try_acquire_lock(&path, LockMode::Exclusive).unwrap().unwrap().is_exclusive());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
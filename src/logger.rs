@@ -1,4 +1,17 @@
 use std::cmp::{PartialOrd, PartialEq};
+use std::cell::RefCell;
+use std::fs::{metadata, remove_file, rename, File, OpenOptions};
+use std::io::{stderr, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
+use crate::secret::SecretString;
+use crate::storage::create_private_dir;
+
+/// JSON fields whose string values are masked in every log line
+const REDACTED_FIELDS: [&str; 3] = ["secret", "password", "key"];
+const MASK: &str = "***";
+const LOG_FILE: &str = "twofa.log";
+
+pub const LOG_VAR: &str = "TWOFA_LOG";
 
 #[derive(PartialOrd, PartialEq)]
 enum LogLevel {
@@ -8,56 +21,499 @@ enum LogLevel {
     Max = 3,
 }
 
+impl LogLevel {
+    fn name(&self) -> &'static str {
+        match self {
+            LogLevel::Norm => "norm",
+            LogLevel::Min => "min",
+            LogLevel::Mid => "mid",
+            LogLevel::Max => "max",
+        }
+    }
+}
+
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+impl LogFormat {
+    pub fn from_name(name: &str) -> Result<Self, &'static str> {
+        match name {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err("Unknown log format. Use 'text' or 'json'"),
+        }
+    }
+}
+
+/// Parses a `TWOFA_LOG` value, either a level name or its number.
+pub fn level_from_name(name: &str) -> Option<i32> {
+    match name.trim().to_lowercase().as_str() {
+        "norm" | "0" => Some(0),
+        "min" | "1" => Some(1),
+        "mid" | "2" => Some(2),
+        "max" | "3" => Some(3),
+        _ => None,
+    }
+}
+
 pub struct Logger {
     level: LogLevel,
+    unsafe_output: bool,
+    format: LogFormat,
+    secrets: RefCell<Vec<SecretString>>,
+    out: RefCell<Box<dyn Write>>,
+    file: RefCell<Option<File>>,
 }
 
-#[allow(dead_code)]
 impl Logger {
-    pub fn new(level: i32) -> Self {
-            let log_level: Option<LogLevel>;
-            match level {
-                0 => {
-                    log_level = Some(LogLevel::Norm);
-                },
-                1 => {
-                    log_level = Some(LogLevel::Min);
-                },
-                2 => {
-                    log_level = Some(LogLevel::Mid);
-                },
-                3 => {
-                    log_level = Some(LogLevel::Max);
-                },
+    pub fn new(level: i32, unsafe_output: bool) -> Self {
+        Self::with_writer(level, unsafe_output, Box::new(stderr()))
+    }
+
+    pub fn with_writer(level: i32, unsafe_output: bool, out: Box<dyn Write>) -> Self {
+            let log_level = match level {
+                0 => LogLevel::Norm,
+                1 => LogLevel::Min,
+                2 => LogLevel::Mid,
+                3 => LogLevel::Max,
                 x => {
-                    println!("LogLevel '{}' not supported. Norm chosen.", x);
-                    log_level = Some(LogLevel::Norm);
+                    eprintln!("LogLevel '{}' not supported. Norm chosen.", x);
+                    LogLevel::Norm
                 }
             };
             Self {
-                level: log_level.unwrap(),
+                level: log_level,
+                unsafe_output,
+                format: LogFormat::Text,
+                secrets: RefCell::new(Vec::new()),
+                out: RefCell::new(out),
+                file: RefCell::new(None),
+            }
+    }
+
+    pub fn set_format(&mut self, format: LogFormat) -> &mut Self {
+        self.format = format;
+        self
+    }
+
+    /// Additionally appends every line to `dir/twofa.log`, rotating the file
+    /// once it grows beyond `max_size` bytes and keeping `max_files` old logs.
+    pub fn set_file(&mut self, dir: &str, max_size: u64, max_files: u32) -> Result<&mut Self, &'static str> {
+        if create_private_dir(dir).is_err() {
+            return Err("Could not create log directory");
+        }
+
+        let path = format!("{}/{}", dir, LOG_FILE);
+        if let Ok(meta) = metadata(&path) {
+            if meta.len() >= max_size {
+                rotate_logs(&path, max_files);
+            }
+        }
+
+        let mut options = OpenOptions::new();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(crate::storage::PRIVATE_FILE_MODE);
+        }
+
+        match options.create(true).append(true).open(&path) {
+            Ok(file) => {
+                self.file = RefCell::new(Some(file));
+                Ok(self)
+            },
+            Err(_) => Err("Could not open log file"),
+        }
+    }
+
+    /// Registers a value that must never show up in log output.
+    pub fn add_secret(&self, secret: &str) {
+        if !secret.is_empty() {
+            self.secrets.borrow_mut().push(SecretString::from(secret));
+        }
+    }
+
+    fn redact(&self, msg: &str) -> String {
+        let mut redacted = redact_json_fields(msg);
+        for secret in self.secrets.borrow().iter() {
+            redacted = redacted.replace(secret.expose(), MASK);
+        }
+        redacted
+    }
+
+    fn json_line(&self, level: &LogLevel, event: &str, msg: &str) -> String {
+        serde_json::json!({
+            "timestamp": timestamp(),
+            "level": level.name(),
+            "event": event,
+            "message": msg,
+        }).to_string()
+    }
+
+    fn write(&self, level: LogLevel, event: &str, msg: &str) {
+        let redacted = self.redact(msg);
+        // --debug-unsafe only uncovers the terminal, the log file stays on disk
+        let shown = if self.unsafe_output { msg.to_owned() } else { redacted.clone() };
+
+        let line = match self.format {
+            LogFormat::Json => self.json_line(&level, event, &shown),
+            LogFormat::Text => {
+                if level == LogLevel::Norm {
+                    shown
+                } else {
+                    format!("DEBUG: {}", shown)
+                }
+            },
+        };
+        let _ = writeln!(self.out.borrow_mut(), "{}", line);
+
+        if let Some(file) = self.file.borrow_mut().as_mut() {
+            let line = match self.format {
+                LogFormat::Json => self.json_line(&level, event, &redacted),
+                LogFormat::Text => format!("{} {} [{}] {}", timestamp(), level.name(), event, redacted),
+            };
+            let _ = writeln!(file, "{}", line);
+        }
+    }
+
+    pub fn norm(&self, event: &str, msg: &str) {
+        self.write(LogLevel::Norm, event, msg);
+    }
+
+    pub fn min(&self, event: &str, msg: &str) {
+        if self.level >= LogLevel::Min {
+            self.write(LogLevel::Min, event, msg);
+        }
+    }
+
+    pub fn mid(&self, event: &str, msg: &str) {
+        if self.level >= LogLevel::Mid {
+            self.write(LogLevel::Mid, event, msg);
+        }
+    }
+
+    pub fn max(&self, event: &str, msg: &str) {
+        if self.level >= LogLevel::Max {
+            self.write(LogLevel::Max, event, msg);
+        }
+    }
+}
+
+fn rotate_logs(path: &str, max_files: u32) {
+    if max_files == 0 {
+        let _ = remove_file(path);
+        return;
+    }
+
+    let _ = remove_file(format!("{}.{}", path, max_files));
+    for i in (1..max_files).rev() {
+        let _ = rename(format!("{}.{}", path, i), format!("{}.{}", path, i + 1));
+    }
+    let _ = rename(path, format!("{}.1", path));
+}
+
+/// Current UTC time as RFC 3339, e.g. `2021-06-01T12:30:00Z`.
+fn timestamp() -> String {
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    format_timestamp(secs)
+}
+
+pub fn format_timestamp(secs: u64) -> String {
+    let days = (secs / 86400) as i64;
+    let rem = secs % 86400;
+
+    // civil date from days since epoch, see http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+            year, month, day, rem / 3600, (rem % 3600) / 60, rem % 60)
+}
+
+/// Seconds since epoch of a time formatted by `format_timestamp`.
+pub fn parse_timestamp(timestamp: &str) -> Option<u64> {
+    let field = |range: std::ops::Range<usize>| timestamp.get(range)?.parse::<i64>().ok();
+    if timestamp.len() != 20 || &timestamp[4..5] != "-" || &timestamp[7..8] != "-" || &timestamp[10..11] != "T"
+        || &timestamp[13..14] != ":" || &timestamp[16..17] != ":" || &timestamp[19..] != "Z" {
+        return None;
+    }
+    let (year, month, day) = (field(0..4)?, field(5..7)?, field(8..10)?);
+    let (hour, minute, second) = (field(11..13)?, field(14..16)?, field(17..19)?);
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) || hour > 23 || minute > 59 || second > 59 {
+        return None;
+    }
+
+    // days since epoch from the civil date, the inverse of `format_timestamp`
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year - era * 400;
+    let doy = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146097 + doe - 719468;
+
+    let secs = days * 86400 + hour * 3600 + minute * 60 + second;
+    if secs < 0 { None } else { Some(secs as u64) }
+}
+
+/// Masks the string values of `REDACTED_FIELDS` in JSON found anywhere in `msg`.
+fn redact_json_fields(msg: &str) -> String {
+    let mut result = String::with_capacity(msg.len());
+    let mut rest = msg;
+
+    while let Some(start) = rest.find('"') {
+        result.push_str(&rest[..start]);
+        rest = &rest[start..];
+
+        let field = REDACTED_FIELDS.iter().find(|f| {
+            rest.len() > f.len() + 1
+                && rest[1..].starts_with(*f)
+                && rest[f.len() + 1..].starts_with('"')
+        });
+
+        let field = match field {
+            Some(f) => f,
+            None => {
+                result.push('"');
+                rest = &rest[1..];
+                continue;
             }
+        };
+
+        let after_key = &rest[field.len() + 2..];
+        let value = after_key.trim_start();
+        if !value.starts_with(':') {
+            result.push('"');
+            rest = &rest[1..];
+            continue;
+        }
+        let value = value[1..].trim_start();
+        if !value.starts_with('"') {
+            result.push('"');
+            rest = &rest[1..];
+            continue;
+        }
+
+        let mut end = None;
+        let mut escaped = false;
+        for (i, c) in value[1..].char_indices() {
+            if escaped {
+                escaped = false;
+            } else if c == '\\' {
+                escaped = true;
+            } else if c == '"' {
+                end = Some(i + 1);
+                break;
+            }
+        }
+
+        match end {
+            Some(end) => {
+                result.push_str(&format!("\"{}\":\"{}\"", field, MASK));
+                rest = &value[end + 1..];
+            },
+            None => {
+                result.push_str(&format!("\"{}\":\"{}", field, MASK));
+                rest = "";
+            }
+        }
+    }
+
+    result.push_str(rest);
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::rc::Rc;
+
+    #[derive(Clone)]
+    struct Sink(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Sink {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn logger(level: i32, unsafe_output: bool) -> (Logger, Sink) {
+        let sink = Sink(Rc::new(RefCell::new(Vec::new())));
+        (Logger::with_writer(level, unsafe_output, Box::new(sink.clone())), sink)
+    }
+
+    fn output(sink: &Sink) -> String {
+        String::from_utf8(sink.0.borrow().clone()).unwrap()
+    }
+
+    const VAULT: &str = r#"{"github":{"encoding":"base32","hash":"sha1","secret":"JBSWY3DPEHPK3PXP","window":30}}"#;
+
+    #[test]
+    fn redacts_vault_secrets_at_every_level() {
+        let (logger, sink) = logger(3, false);
+
+        logger.norm("test", VAULT);
+        logger.min("test", format!("Data from file: \n {}", VAULT).as_str());
+        logger.mid("test", VAULT);
+        logger.max("test", VAULT);
+
+        let out = output(&sink);
+        assert!(!out.contains("JBSWY3DPEHPK3PXP"));
+        assert_eq!(out.matches(r#""secret":"***""#).count(), 4);
+        assert!(out.contains(r#""hash":"sha1""#));
+    }
+
+    #[test]
+    fn redacts_pretty_printed_json() {
+        let (logger, sink) = logger(0, false);
+
+        logger.norm("test", "{\n  \"secret\" : \"JBSWY3DPEHPK3PXP\",\n  \"window\": 30\n}");
+
+        assert!(!output(&sink).contains("JBSWY3DPEHPK3PXP"));
+    }
+
+    #[test]
+    fn redacts_escaped_quotes_in_secret() {
+        let (logger, sink) = logger(0, false);
+
+        logger.norm("test", r#"{"secret":"ab\"cd","window":30}"#);
+
+        let out = output(&sink);
+        assert!(!out.contains("cd"));
+        assert!(out.contains(r#""window":30"#));
     }
 
-    pub fn norm(&self, msg: &str) -> () {
-        println!("{}", msg);
+    #[test]
+    fn redacts_registered_secrets() {
+        let (logger, sink) = logger(1, false);
+        logger.add_secret("hunter2");
+
+        logger.norm("test", "password is hunter2");
+        logger.min("test", "s: hunter2; h: SHA1");
+
+        let out = output(&sink);
+        assert!(!out.contains("hunter2"));
     }
 
-    pub fn min(&self, msg: &str) -> () {
-        if &self.level >= &LogLevel::Min {
-            println!("DEBUG: {}", msg);
+    #[test]
+    fn debug_messages_respect_level() {
+        let (logger, sink) = logger(0, false);
+
+        logger.min("test", "hidden");
+
+        assert!(output(&sink).is_empty());
+    }
+
+    #[test]
+    fn unsafe_output_prints_plaintext() {
+        let (logger, sink) = logger(1, true);
+        logger.add_secret("hunter2");
+
+        logger.min("test", VAULT);
+        logger.min("test", "hunter2");
+
+        let out = output(&sink);
+        assert!(out.contains("JBSWY3DPEHPK3PXP"));
+        assert!(out.contains("hunter2"));
+    }
+
+    #[test]
+    fn unsafe_output_keeps_log_file_redacted() {
+        let dir = crate::test_util::temp_dir("logger-unsafe");
+
+        {
+            let (mut logger, sink) = logger(1, true);
+            logger.set_file(&dir, 1024 * 1024, 1).unwrap();
+            logger.add_secret("hunter2");
+            logger.min("test", format!("Data from file: \n {}", VAULT).as_str());
+            logger.min("test", "hunter2");
+            assert!(output(&sink).contains("JBSWY3DPEHPK3PXP"));
         }
+
+        let written = std::fs::read_to_string(format!("{}/{}", dir, LOG_FILE)).unwrap();
+        assert!(!written.contains("JBSWY3DPEHPK3PXP"));
+        assert!(!written.contains("hunter2"));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn json_format_has_event_level_and_timestamp() {
+        let (mut logger, sink) = logger(1, false);
+        logger.set_format(LogFormat::Json);
+
+        logger.min("decrypt", r#"{"secret":"JBSWY3DPEHPK3PXP"}"#);
+
+        let out = output(&sink);
+        assert!(!out.contains("JBSWY3DPEHPK3PXP"));
+
+        let line: serde_json::Value = serde_json::from_str(out.trim()).unwrap();
+        assert_eq!(line["event"], "decrypt");
+        assert_eq!(line["level"], "min");
+        assert!(line["timestamp"].as_str().unwrap().ends_with('Z'));
     }
 
-    pub fn mid(&self, msg: &str) -> () {
-        if &self.level >= &LogLevel::Mid {
-            println!("DEBUG: {}", msg);
+    #[test]
+    fn formats_timestamps_as_rfc3339() {
+        assert_eq!(format_timestamp(0), "1970-01-01T00:00:00Z");
+        assert_eq!(format_timestamp(951782400), "2000-02-29T00:00:00Z");
+        assert_eq!(format_timestamp(1622550645), "2021-06-01T12:30:45Z");
+    }
+
+    #[test]
+    fn parses_level_names() {
+        assert_eq!(level_from_name("mid"), Some(2));
+        assert_eq!(level_from_name("MAX"), Some(3));
+        assert_eq!(level_from_name("0"), Some(0));
+        assert_eq!(level_from_name("verbose"), None);
+    }
+
+    #[test]
+    fn writes_and_rotates_log_file() {
+        let dir = std::env::temp_dir().join(format!("twofa-log-test-{}", std::process::id()));
+        let dir = dir.to_str().unwrap();
+        let path = format!("{}/{}", dir, LOG_FILE);
+
+        {
+            let (mut logger, _sink) = logger(0, false);
+            logger.set_file(dir, 1, 2).unwrap();
+            logger.norm("first", "first run");
+        }
+        {
+            let (mut logger, _sink) = logger(0, false);
+            logger.set_file(dir, 1, 2).unwrap();
+            logger.norm("second", "second run");
         }
+
+        let current = std::fs::read_to_string(&path).unwrap();
+        let rotated = std::fs::read_to_string(format!("{}.1", path)).unwrap();
+        assert!(current.contains("[second] second run"));
+        assert!(rotated.contains("[first] first run"));
+
+        std::fs::remove_dir_all(dir).unwrap();
     }
 
-    pub fn max(&self, msg: &str) -> () {
-        if &self.level >= &LogLevel::Max {
-            println!("DEBUG: {}", msg);
+    #[test]
+    fn parses_formatted_timestamps() {
+        for secs in [0, 951782400, 1625412600, 4102444799] {
+            assert_eq!(parse_timestamp(&format_timestamp(secs)), Some(secs));
         }
+        assert_eq!(parse_timestamp("2021-07-04T15:30:00"), None);
+        assert_eq!(parse_timestamp("2021-13-04T15:30:00Z"), None);
     }
 }
@@ -3,294 +3,1189 @@ mod crypto;
 mod twofa;
 mod logger;
 mod helper;
+mod keyring;
+mod config;
+mod session;
+mod secret;
+mod cli;
+mod error;
+mod vault;
+mod migrate;
+mod lock;
+mod backup;
+mod recovery;
+mod permissions;
+mod location;
+mod profile;
+mod backend;
+mod clock;
+mod actions;
+mod history;
+mod merge;
+mod diff;
+#[cfg(test)]
+mod test_util;
 
-use clap::{AppSettings, Clap};
-use serde_json::{from_str, Result as SerdeResult, Value, Map, Number};
-use crate::storage::{read_storage, FileReadError, save_storage, delete_file, get_storage_path, Storage, check_storage};
-use crate::crypto::{encrypt_file, decrypt_file};
-use crate::twofa::{create_twofa_settings, create_code_with_twofa_settings, create_twofa_settings_with_input};
-use crate::logger::{Logger};
-use crate::helper::{prompt_for_input, merge_json, create_folder};
+use clap::Clap;
+use crate::storage::{read_storage, save_storage, copy_storage, delete_file, get_storage_path, config_file, Storage, check_storage};
+use crate::crypto::{derive_key, key_from_bytes, write_key_check, matches_key_check, KEY_SIZE};
+use crate::twofa::{create_code, create_entry_with_input};
+use crate::logger::{Logger, LogFormat, level_from_name, LOG_VAR};
+use crate::helper::{prompt_for_input, prompt_for_password, create_folder};
+use crate::keyring::{Keyring, store_key, read_key, revoke_key};
+use crate::config::{read_config, config_entries, set_config_value, TwofaConfig};
+use crate::session::{create_session, open_session, end_session, SESSION_VAR};
+use crate::secret::{SecretString, VaultKey, harden_process};
+use crate::cli::{Opts, Command, AddOpts, GetOpts, ShowOpts, RmOpts, RenameOpts, ImportOpts, ExportOpts, BackupCommand, RestoreOpts, ProfileCommand, ProfileCreateOpts, ProfileDeleteOpts, ConfigCommand, ConfigGetOpts, ConfigSetOpts, DoctorOpts, HistoryCommand, HistoryRestoreOpts, MigrateBackendOpts, MergeOpts, DiffOpts};
+use crate::error::TwofaError;
+use crate::vault::{Entry, Vault};
+use crate::migrate::SCHEMA_VERSION;
+use crate::lock::{acquire_lock, LockMode, VaultLock};
+use crate::backup::{list_backups, find_backup};
+use crate::permissions::{check_permissions, check_storage_permissions, fix_permissions};
+use crate::recovery::{install_cleanup_handlers, find_leftover_buffers, recoverable_buffer, wipe_leftovers, wipe_buffers, is_interactive};
+use crate::location::{find_home, find_vault_dir};
+use crate::backend::{open_backend, storage_backend, backend_for_file, backend_for_foreign_file, move_to_backend, take_entry, BackendKind, VaultBackend};
+use crate::clock::{Clock, SystemClock};
+use crate::actions::{check_entry, set_secret};
+use crate::diff::{diff_vaults, open_copy};
+use crate::merge::{merge_vaults, Conflict, Side, State};
+use crate::history::{record_change, list_history, checkout_revision, has_history};
+use crate::profile::{deletable_profile_dir, list_profiles, new_profile_dir, profile_dir, DEFAULT_PROFILE, PROFILE_VAR};
+use crate::logger::format_timestamp;
 use clipboard::{ClipboardContext, ClipboardProvider};
-
-#[derive(Clap)]
-#[clap(version = "1.0.0", author = "Paul D. <paullenardo@gmail.com>")]
-#[clap(setting = AppSettings::ColoredHelp)]
-pub struct Opts {
-    /// get / set / init
-    action: String,
-    #[clap(short, long)]
-    /// name of application
-    application: Option<String>,
-    #[clap(short, long)]
-    /// provide password
-    password: String,
-    #[clap(short, long)]
-    /// set secret
-    secret: Option<String>,
-    #[clap(short, long, default_value = "30")]
-    /// set window
-    window: Number,
-    #[clap(short, long, default_value = "sha1")]
-    /// set hash
-    hash: String,
-    #[clap(short, long, default_value = "base32")]
-    /// set encoding
-    encoding: String,
-    #[clap(short, long, parse(from_occurrences))]
-    /// copy to clipboard
-    copy: i32,
-    #[clap(short, long, parse(from_occurrences))]
-    /// set debug level
-    debug: i32,
-}
+use std::fs::remove_dir_all;
 
 fn main() {
+    harden_process();
+
     let opts: Opts = Opts::parse();
-    let logger: Logger = Logger::new(opts.debug.clone());
-    let storage_path = get_storage_path();
-
-    let action = &opts.action.clone();
-    let application = &opts.application.clone();
-    if let Some(app) = application {
-        logger.min(
-            format!("Action: {}, App: {}", &opts.action.clone(), &app,
-            ).as_str());
-    } else {
-        logger.min(
-            format!("Action: {}", &action)
-                .as_str()
-        );
+    let storage_path = match locate_storage(&opts) {
+        Ok(s) => s,
+        Err(e) => exit_with(e),
+    };
+    install_cleanup_handlers(&storage_path);
+
+    let config = match read_config(&storage_path.config[..], storage_path.profile_config.as_deref()) {
+        Ok(c) => c,
+        Err(e) => exit_with(TwofaError::from(e)),
+    };
+
+    let logger = create_logger(&opts, &storage_path, &config);
+    if let Some(password) = &opts.password {
+        logger.add_secret(password.expose());
+    }
+    if let Command::Add(add_opts) = &opts.command {
+        if let Some(secret) = &add_opts.secret {
+            logger.add_secret(secret.expose());
+        }
     }
 
-    match &opts.action[..] {
-        "set" => {
-            set_secret(opts, storage_path, logger).expect("Failed to set secret");
+    if let Command::Doctor(doctor_opts) = &opts.command {
+        if let Err(e) = run_doctor(doctor_opts, &storage_path, &logger) {
+            logger.min("exit", format!("Exiting with code {}", e.exit_code()).as_str());
+            exit_with(e);
+        }
+        return;
+    }
+
+    if let Err(e) = check_profile(&opts, &storage_path)
+        .and_then(|_| check_storage_permissions(&storage_path, config.permissions.strict, &logger))
+        .and_then(|_| recover_buffers(&opts, &storage_path, &config, &logger)) {
+        logger.min("exit", format!("Exiting with code {}", e.exit_code()).as_str());
+        exit_with(e);
+    }
+
+    let result = match &opts.command {
+        Command::Init => create_storage(&opts, &storage_path, &config, &logger),
+        Command::Add(add_opts) => add_secret(&opts, add_opts, &storage_path, &config, &logger),
+        Command::Get(get_opts) => get_code(&opts, get_opts, &storage_path, &config, &logger),
+        Command::Show(show_opts) => show_application(&opts, show_opts, &storage_path, &config, &logger),
+        Command::List => list_applications(&opts, &storage_path, &config, &logger),
+        Command::Rm(rm_opts) => remove_application(&opts, rm_opts, &storage_path, &config, &logger),
+        Command::Rename(rename_opts) => rename_application(&opts, rename_opts, &storage_path, &config, &logger),
+        Command::Import(import_opts) => import_storage(&opts, import_opts, &storage_path, &config, &logger),
+        Command::Export(export_opts) => export_storage(&opts, export_opts, &storage_path, &config, &logger),
+        Command::Backup(backup_opts) => match &backup_opts.command {
+            BackupCommand::List => list_storage_backups(&storage_path),
+            BackupCommand::Restore(restore_opts) => restore_storage_backup(&opts, restore_opts, &storage_path, &config, &logger),
         },
-        "get" => {
-            get_code(opts, storage_path, logger).expect("Failed to get code");
+        Command::Unlock => start_session(&opts, &storage_path, &config, &logger),
+        Command::Lock => lock_storage(&storage_path, &config, &logger),
+        Command::Profile(profile_opts) => match &profile_opts.command {
+            ProfileCommand::List => list_storage_profiles(&storage_path),
+            ProfileCommand::Create(create_opts) => create_profile(&opts, create_opts, &storage_path, &logger),
+            ProfileCommand::Delete(delete_opts) => delete_profile(delete_opts, &storage_path, &config, &logger),
         },
-        "init" => {
-            create_storage(opts, storage_path, logger).expect("Failed to create storage");
+        Command::Config(config_opts) => match &config_opts.command {
+            ConfigCommand::List => list_config(&config),
+            ConfigCommand::Get(get_opts) => get_config(get_opts, &config),
+            ConfigCommand::Set(set_opts) => set_config(set_opts, &storage_path),
         },
-        _ => {
-            println!("Action '{}' not supported", &opts.action);
-            std::process::exit(1);
-        }
+        Command::Doctor(_) => Ok(()),
+        Command::History(history_opts) => match &history_opts.command {
+            None | Some(HistoryCommand::List) => list_storage_history(&storage_path, &logger),
+            Some(HistoryCommand::Restore(restore_opts)) => restore_history_revision(&opts, restore_opts, &storage_path, &config, &logger),
+        },
+        Command::MigrateBackend(migrate_opts) => migrate_backend(&opts, migrate_opts, &storage_path, &config, &logger),
+        Command::Merge(merge_opts) => merge_storage(&opts, merge_opts, &storage_path, &config, &logger),
+        Command::Diff(diff_opts) => diff_storages(&opts, diff_opts, &storage_path, &config, &logger),
+    };
+
+    if let Err(e) = result {
+        logger.min("exit", format!("Exiting with code {}", e.exit_code()).as_str());
+        exit_with(e);
+    }
+
+    wipe_buffers();
+}
+
+fn exit_with(error: TwofaError) -> ! {
+    // a failed decryption can leave partly decrypted content behind
+    wipe_buffers();
+    eprintln!("{}", error);
+    std::process::exit(error.exit_code());
+}
+
+fn locate_storage(opts: &Opts) -> Result<Storage, TwofaError> {
+    if opts.vault.is_some() && opts.profile.is_some() {
+        return Err(TwofaError::Other("--vault and --profile cannot be used together"));
     }
+
+    let home = find_home()?;
+    let default_profile = read_config(&config_file(&home[..])[..], None)?.default_profile;
+    let (dir, profile) = find_vault_dir(&home[..], opts.vault.as_deref(), opts.profile.as_deref(), &default_profile[..])?;
+
+    Ok(get_storage_path(home, dir, profile))
 }
 
-fn set_secret(opts: Opts, storage_path: Storage, logger: Logger) -> Result<(), &'static str>{
-    let app = opts.application.clone().unwrap();
-    let twofa_settings = create_twofa_settings_with_input(&opts).unwrap();
+/// Named profiles only exist once created, a typo must not end up as an
+/// empty storage.
+fn check_profile(opts: &Opts, storage_path: &Storage) -> Result<(), TwofaError> {
+    if let Command::Init | Command::Profile(_) = &opts.command {
+        return Ok(());
+    }
 
-    logger.min(
-        format!("Created settings: {}", twofa_settings.to_string())
-            .as_str()
-    );
+    match &storage_path.profile {
+        Some(name) if name != DEFAULT_PROFILE && !check_storage(&storage_path.dir[..]) => {
+            Err(TwofaError::ProfileNotFound(name.clone()))
+        },
+        _ => Ok(()),
+    }
+}
+
+fn output_json(opts: &Opts, config: &TwofaConfig) -> bool {
+    opts.format.as_deref().unwrap_or(&config.output.format[..]) == "json"
+}
 
-    if let Err(_) = decrypt_file(&storage_path.en_file[..], &storage_path.de_file[..], &opts.password, &logger) {
-        println!("Could not decrypt file");
-        std::process::exit(1);
+fn create_logger(opts: &Opts, storage_path: &Storage, config: &TwofaConfig) -> Logger {
+    let mut level = opts.debug;
+    if level == 0 {
+        if let Ok(name) = std::env::var(LOG_VAR) {
+            match level_from_name(&name[..]) {
+                Some(l) => level = l,
+                None => eprintln!("{} '{}' not supported. Norm chosen.", LOG_VAR, name),
+            }
+        }
+    }
+
+    let mut logger = Logger::new(level, opts.debug_unsafe);
+
+    let format_name = match &opts.log_format {
+        Some(f) => f.clone(),
+        None => config.log.format.clone(),
     };
+    match LogFormat::from_name(&format_name[..]) {
+        Ok(format) => {
+            logger.set_format(format);
+        },
+        Err(e) => exit_with(TwofaError::from(e)),
+    }
 
-    let mut data_from_file: String = String::new();
+    if config.log.file {
+        if let Err(e) = logger.set_file(&storage_path.log_dir[..], config.log.max_size, config.log.max_files) {
+            logger.norm("log_file_error", e);
+        }
+    }
+
+    logger
+}
 
-    match read_storage(&storage_path.de_file[..]) {
-        Ok(data) => {
-            data_from_file.push_str(&data[..]);
+fn get_password(opts: &Opts, logger: &Logger) -> Result<SecretString, TwofaError> {
+    match &opts.password {
+        Some(password) => Ok(SecretString::from(password.expose())),
+        None => {
+            let password = prompt_for_password("Password")?;
+            logger.add_secret(password.expose());
+            Ok(password)
         },
+    }
+}
+
+fn key_description(storage_path: &Storage) -> String {
+    format!("twofa:{}", &storage_path.en_file)
+}
+
+fn cache_key(key: &VaultKey, storage_path: &Storage, config: &TwofaConfig, logger: &Logger) {
+    if !config.key_cache.enabled {
+        return;
+    }
+
+    let keyring = match Keyring::from_name(&config.key_cache.keyring[..]) {
+        Ok(k) => k,
         Err(e) => {
-            match e {
-                FileReadError::NoContent => { return Err("No content") },
-                FileReadError::NoFile => {
-
-                    if !check_storage(&storage_path.de_file[..]) {
-                        if let Err(_) = save_storage(&storage_path.de_file[..], String::from("{}")) {
-                            println!("Could not save storage");
-                            std::process::exit(1);
-                        }
-                        data_from_file.push_str("{}");
-                    } else {
-                        logger.norm("file exists but could not be read");
-                        std::process::exit(1);
-                    }
-                }
-            };
-        },
+            logger.norm("key_cache_error", e);
+            return;
+        }
     };
 
-    logger.min(
-        format!("Data from file: \n {}", data_from_file)
+    if let Err(e) = store_key(&key_description(storage_path)[..], &key.expose()[..], config.key_cache.timeout, &keyring, logger) {
+        logger.norm("key_cache_error", e);
+    }
+}
+
+/// Opens the storage through `backend` with the session key, the cached key
+/// or the password, in that order. Returns the key and content together with
+/// whether the storage is still in the legacy format.
+fn unlock_storage(opts: &Opts, storage_path: &Storage, config: &TwofaConfig, backend: &dyn VaultBackend, logger: &Logger) -> Result<(VaultKey, Vault, bool), TwofaError> {
+    unlock_with(
+        opts, storage_path, config, logger,
+        |key| backend.load(key, logger),
+        |password| backend.load_legacy(password, logger),
+    )
+}
+
+/// Tries the session key, the cached key and the password, in that order,
+/// until `load` stops failing with a wrong key. `load_legacy` is the last
+/// resort for storages that need the password itself.
+fn unlock_with<T>(
+    opts: &Opts,
+    storage_path: &Storage,
+    config: &TwofaConfig,
+    logger: &Logger,
+    load: impl Fn(&VaultKey) -> Result<T, TwofaError>,
+    load_legacy: impl Fn(&SecretString) -> Result<T, TwofaError>,
+) -> Result<(VaultKey, T, bool), TwofaError> {
+    let description = key_description(storage_path);
+
+    if let (Ok(token), None) = (std::env::var(SESSION_VAR), &opts.password) {
+        match open_session(&storage_path.session[..], &description[..], &token[..], &SystemClock, logger) {
+            Ok(key) => match load(&key) {
+                Err(TwofaError::WrongPassword) => logger.min("session_mismatch", "Session key does not match storage"),
+                result => return result.map(|loaded| (key, loaded, false)),
+            },
+            Err(e) => logger.min("session_invalid", e),
+        }
+    }
+
+    if config.key_cache.enabled && opts.password.is_none() {
+        let keyring = Keyring::from_name(&config.key_cache.keyring[..])?;
+        let cached = read_key(&description[..], &keyring, KEY_SIZE, logger)
+            .and_then(|bytes| key_from_bytes(&bytes[..]));
+
+        if let Some(key) = cached {
+            match load(&key) {
+                Err(TwofaError::WrongPassword) => {
+                    logger.min("key_mismatch", "Cached key does not match storage");
+                    revoke_key(&description[..], &keyring, logger);
+                },
+                result => return result.map(|loaded| (key, loaded, false)),
+            }
+        }
+    }
+
+    let password = get_password(opts, logger)?;
+    let key = derive_key(&password, logger)?;
+
+    let (loaded, legacy) = match load(&key) {
+        Ok(loaded) => (loaded, false),
+        Err(TwofaError::WrongPassword) => (load_legacy(&password)?, true),
+        Err(e) => return Err(e),
+    };
+
+    cache_key(&key, storage_path, config, logger);
+
+    Ok((key, loaded, legacy))
+}
+
+/// Opens a copy that does not belong to this storage, e.g. one from another
+/// machine, with `known` or the password. Unlike `unlock_storage`, neither
+/// the session nor the key cache of this storage is read or written.
+fn unlock_foreign(opts: &Opts, backend: &dyn VaultBackend, known: Option<&VaultKey>, logger: &Logger) -> Result<(VaultKey, Vault), TwofaError> {
+    if let Some(key) = known {
+        match backend.load(key, logger) {
+            Err(TwofaError::WrongPassword) => logger.min("foreign_key_mismatch", "Key does not match the other copy"),
+            result => {
+                let key = key_from_bytes(&key.expose()[..]).ok_or(TwofaError::Other("Could not copy key"))?;
+                return result.map(|vault| (key, vault));
+            },
+        }
+    }
+
+    let password = get_password(opts, logger)?;
+    let key = derive_key(&password, logger)?;
+
+    let vault = match backend.load(&key, logger) {
+        Err(TwofaError::WrongPassword) => backend.load_legacy(&password, logger)?,
+        result => result?,
+    };
+
+    Ok((key, vault))
+}
+
+fn start_session(opts: &Opts, storage_path: &Storage, config: &TwofaConfig, logger: &Logger) -> Result<(), TwofaError> {
+    let (_lock, key, _vault) = load_storage(opts, storage_path, config, LockMode::Shared, logger)?;
+
+    let token = create_session(&storage_path.session[..], &key_description(storage_path)[..], &key, config.session.timeout, &SystemClock, logger)?;
+
+    println!("export {}=\"{}\"", SESSION_VAR, token);
+
+    Ok(())
+}
+
+fn lock_storage(storage_path: &Storage, config: &TwofaConfig, logger: &Logger) -> Result<(), TwofaError> {
+    let keyring = Keyring::from_name(&config.key_cache.keyring[..])?;
+
+    let revoked = revoke_key(&key_description(storage_path)[..], &keyring, logger);
+    let ended = end_session(&storage_path.session[..], logger);
+
+    if revoked || ended {
+        println!("Storage locked");
+    } else {
+        println!("No cached key or session found");
+    }
+
+    Ok(())
+}
+
+fn run_doctor(doctor_opts: &DoctorOpts, storage_path: &Storage, logger: &Logger) -> Result<(), TwofaError> {
+    let issues = check_permissions(storage_path);
+    if issues.is_empty() {
+        println!("No problems found in '{}'", &storage_path.dir);
+        return Ok(());
+    }
+
+    let mut remaining = None;
+    for issue in issues {
+        println!("{}: {}", &issue.path, &issue.problem);
+
+        if doctor_opts.fix && issue.fixable {
+            fix_permissions(&issue)?;
+            logger.min(
+                "permissions_fixed",
+                format!("Restricted '{}' to its owner", &issue.path)
+                    .as_str()
+            );
+            println!("  fixed");
+        } else if remaining.is_none() {
+            remaining = Some(issue.path);
+        }
+    }
+
+    match remaining {
+        Some(path) => Err(TwofaError::InsecurePermissions(path)),
+        None => Ok(()),
+    }
+}
+
+/// Handles buffer files a crashed or killed twofa left in the storage
+/// directory. They hold the decrypted storage, so they are either encrypted
+/// back into the storage or overwritten and removed.
+fn recover_buffers(opts: &Opts, storage_path: &Storage, config: &TwofaConfig, logger: &Logger) -> Result<(), TwofaError> {
+    if find_leftover_buffers(storage_path).is_empty() {
+        return Ok(());
+    }
+
+    let _lock = acquire_lock(&storage_path.lock[..], LockMode::Exclusive, config.lock.timeout, logger)?;
+    let leftovers = find_leftover_buffers(storage_path);
+    if leftovers.is_empty() {
+        return Ok(());
+    }
+
+    // this runs before every command, so stdout is left to the command itself
+    logger.norm(
+        "buffers_found",
+        format!("Found {} unencrypted buffer file(s) left by an interrupted twofa:", leftovers.len())
             .as_str()
     );
+    for path in leftovers.iter() {
+        logger.norm("buffers_found", format!("  {}", path).as_str());
+    }
 
-    let deserialized_data: SerdeResult<Value> = from_str(data_from_file.as_str());
-    if let SerdeResult::Err(_) = deserialized_data {
-        return Err("Could not parse storage from file");
+    let recovered = recoverable_buffer(&leftovers, is_interactive(), || {
+        Ok(prompt_for_input("Re-encrypt the newest one as the storage or wipe them ? [r/W] ")?.eq("r"))
+    })?;
+
+    if let Some(vault) = recovered {
+        let backend = open_backend(storage_path, config)?;
+        let key = if backend.exists() {
+            unlock_storage(opts, storage_path, config, &*backend, logger)?.0
+        } else {
+            derive_key(&get_password(opts, logger)?, logger)?
+        };
+
+        write_storage(storage_path, &key, &vault, config, logger)?;
+        record_history(storage_path, config, "Recover interrupted change", logger);
+        logger.norm(
+            "buffer_recovered",
+            format!("Re-encrypted {} applications into the storage", vault.entries.len())
+                .as_str()
+        );
     }
 
-    let mut data = deserialized_data.unwrap();
+    wipe_leftovers(&leftovers, logger)?;
+    logger.norm(
+        "buffers_wiped",
+        format!("Wiped {} leftover buffer file(s)", leftovers.len())
+            .as_str()
+    );
 
-    if &data[&app] != &Value::Null {
-        let user_prompt = prompt_for_input("Application is already configured. Overwrite ? [y/N] ").unwrap();
-        if user_prompt.ne(&String::from("y")) {
-            println!("Stopping action");
-            if let Err(_) = delete_file(&storage_path.de_file[..], &logger) {
-                println!("Could not delete decrypted file");
-            }
-            std::process::exit(0);
+    Ok(())
+}
+
+/// Locks and decrypts the storage and returns the lock and key together with
+/// the parsed content.
+fn load_storage(opts: &Opts, storage_path: &Storage, config: &TwofaConfig, mode: LockMode, logger: &Logger) -> Result<(VaultLock, VaultKey, Vault), TwofaError> {
+    let backend = open_backend(storage_path, config)?;
+    if !backend.exists() {
+        return Err(TwofaError::VaultNotFound);
+    }
+
+    let lock = acquire_lock(&storage_path.lock[..], mode, config.lock.timeout, logger)?;
+    let (key, vault, legacy) = unlock_storage(opts, storage_path, config, &*backend, logger)?;
+
+    if !lock.is_exclusive() {
+        return Ok((lock, key, vault));
+    }
+
+    if let Some(from) = vault.migrated_from {
+        let backup = format!("{}.v{}.bak", backend.path(), from);
+        if copy_storage(backend.path(), &backup[..]).is_err() {
+            return Err(TwofaError::Io("Could not back up storage before upgrading it"));
         }
+        write_storage(storage_path, &key, &vault, config, logger)?;
+        record_history(storage_path, config, &format!("Upgrade storage from version {} to {}", from, SCHEMA_VERSION)[..], logger);
+
+        logger.norm(
+            "storage_migrated",
+            format!("Storage upgraded from version {} to {}, the old one is kept at '{}'", from, SCHEMA_VERSION, backup)
+                .as_str()
+        );
+    } else if legacy {
+        // rewrite in the current format so the key alone opens it from now on
+        write_storage(storage_path, &key, &vault, config, logger)?;
+    } else if matches_key_check(&storage_path.key_check[..], &key).is_none() {
+        write_key_check(&storage_path.key_check[..], &key)?;
     }
 
-    let json_data = serde_json::json!({
-        app: twofa_settings.to_json()
-    });
+    let configured = BackendKind::from_name(&config.storage.backend[..])?;
+    if backend.kind() != configured {
+        move_storage(storage_path, config, &key, &vault, configured, logger)?;
+    }
 
-    merge_json(&mut data, json_data);
+    Ok((lock, key, vault))
+}
+
+/// Whether the storage is kept in another backend than configured, and is
+/// moved once it is unlocked with the exclusive lock.
+fn backend_outdated(backend: &dyn VaultBackend, config: &TwofaConfig) -> Result<bool, TwofaError> {
+    Ok(backend.kind() != BackendKind::from_name(&config.storage.backend[..])?)
+}
+
+/// Locks the storage and decrypts only the application `app`, as far as the
+/// backend allows.
+fn load_entry(opts: &Opts, storage_path: &Storage, config: &TwofaConfig, app: &str, logger: &Logger) -> Result<(VaultLock, Entry), TwofaError> {
+    let backend = open_backend(storage_path, config)?;
+    if !backend.exists() {
+        return Err(TwofaError::VaultNotFound);
+    }
+
+    if backend_outdated(&*backend, config)? {
+        let (lock, _key, vault) = load_storage(opts, storage_path, config, LockMode::Exclusive, logger)?;
+        return Ok((lock, take_entry(vault, app)?));
+    }
+
+    let lock = acquire_lock(&storage_path.lock[..], LockMode::Shared, config.lock.timeout, logger)?;
+    let (_key, entry, _legacy) = unlock_with(
+        opts, storage_path, config, logger,
+        |key| backend.load_entry(key, app, logger),
+        |password| take_entry(backend.load_legacy(password, logger)?, app),
+    )?;
+
+    Ok((lock, entry))
+}
+
+/// Saves `vault` as the new storage content through the backend of the
+/// storage, after backing up the current one.
+fn write_storage(storage_path: &Storage, key: &VaultKey, vault: &Vault, config: &TwofaConfig, logger: &Logger) -> Result<(), TwofaError> {
+    storage_backend(storage_path, config)?.save(key, vault, logger)
+}
+
+/// Asks a yes/no question, only "y" counts as yes.
+fn confirm(question: &str) -> Result<bool, TwofaError> {
+    Ok(prompt_for_input(question)?.eq("y"))
+}
+
+fn add_secret(opts: &Opts, add_opts: &AddOpts, storage_path: &Storage, config: &TwofaConfig, logger: &Logger) -> Result<(), TwofaError>{
+    let app = add_opts.application.clone();
+
+    let secret = match &add_opts.secret {
+        Some(secret) => SecretString::from(secret.expose()),
+        None => {
+            let secret = prompt_for_password("Secret")?;
+            logger.add_secret(secret.expose());
+            secret
+        }
+    };
+
+    let entry = create_entry_with_input(add_opts, secret, &config.defaults)?;
+    // fail before asking for the password instead of on the first `get`
+    check_entry(&entry)?;
 
     logger.min(
-        format!("Merged data: \n {}", &data.to_string())
+        "entry_created",
+        format!("Created entry: {}", serde_json::to_string(&entry).unwrap_or_default())
             .as_str()
     );
 
-    match save_storage(&storage_path.de_file[..], data.to_string()) {
-        Err(_) => {
-            println!("Could not save storage");
-            std::process::exit(1);
+    let (_lock, key, mut vault) = load_storage(opts, storage_path, config, LockMode::Exclusive, logger)?;
+    let backend = storage_backend(storage_path, config)?;
+    let message = if vault.entries.contains_key(&app) { format!("Replace {}", &app) } else { format!("Add {}", &app) };
+
+    set_secret(&backend, &key, &mut vault, &app, entry, || {
+        Ok(add_opts.force || confirm("Application is already configured. Overwrite ? [y/N] ")?)
+    }, &SystemClock, logger)?;
+
+    record_history(storage_path, config, &message[..], logger);
+    Ok(())
+}
+
+fn get_code(opts: &Opts, get_opts: &GetOpts, storage_path: &Storage, config: &TwofaConfig, logger: &Logger) -> Result<(), TwofaError>{
+    let backend = open_backend(storage_path, config)?;
+    if !backend.exists() {
+        return Err(TwofaError::VaultNotFound);
+    }
+
+    let code = if backend_outdated(&*backend, config)? {
+        let (_lock, entry) = load_entry(opts, storage_path, config, &get_opts.application, logger)?;
+        create_code(&entry, &SystemClock)?
+    } else {
+        let _lock = acquire_lock(&storage_path.lock[..], LockMode::Shared, config.lock.timeout, logger)?;
+        unlock_with(
+            opts, storage_path, config, logger,
+            |key| actions::get_code(&*backend, key, &get_opts.application, &SystemClock, logger),
+            |password| create_code(&take_entry(backend.load_legacy(password, logger)?, &get_opts.application)?, &SystemClock),
+        )?.1
+    };
+
+    // without a display, e.g. over SSH, the code is still printed
+    if get_opts.copy || config.clipboard.copy {
+        let copied = ClipboardProvider::new()
+            .and_then(|mut ctx: ClipboardContext| ctx.set_contents(code.clone()));
+        if copied.is_err() {
+            logger.norm("clipboard_failed", "Warning: could not copy code to clipboard");
+        }
+    }
+
+    if output_json(opts, config) {
+        println!("{}", serde_json::json!({
+            "application": &get_opts.application,
+            "code": code,
+        }));
+    } else {
+        println!("Code: {}", code);
+    }
+
+    Ok(())
+}
+
+fn show_application(opts: &Opts, show_opts: &ShowOpts, storage_path: &Storage, config: &TwofaConfig, logger: &Logger) -> Result<(), TwofaError> {
+    let (_lock, entry) = load_entry(opts, storage_path, config, &show_opts.application, logger)?;
+
+    if output_json(opts, config) {
+        let mut shown = serde_json::json!({
+            "application": &show_opts.application,
+            "issuer": &entry.issuer,
+            "hash": entry.hash.name(),
+            "window": entry.window,
+            "digits": entry.digits,
+            "encoding": entry.encoding.name(),
+        });
+        if show_opts.reveal {
+            shown["secret"] = serde_json::Value::from(entry.secret.expose());
+        }
+        println!("{}", shown);
+        return Ok(());
+    }
+
+    println!("Application: {}", &show_opts.application);
+    if let Some(issuer) = &entry.issuer {
+        println!("Issuer: {}", issuer);
+    }
+    println!("Hash: {}", entry.hash.name());
+    println!("Window: {}", entry.window);
+    println!("Digits: {}", entry.digits);
+    println!("Encoding: {}", entry.encoding.name());
+    if show_opts.reveal {
+        println!("Secret: {}", entry.secret.expose());
+    }
+
+    Ok(())
+}
+
+fn list_applications(opts: &Opts, storage_path: &Storage, config: &TwofaConfig, logger: &Logger) -> Result<(), TwofaError> {
+    let (_lock, _key, vault) = load_storage(opts, storage_path, config, LockMode::Shared, logger)?;
+
+    if output_json(opts, config) {
+        let listed: Vec<serde_json::Value> = vault.entries.iter()
+            .map(|(app, entry)| serde_json::json!({
+                "application": app,
+                "issuer": &entry.issuer,
+                "algorithm": entry.hash.name(),
+                "period": entry.window,
+                "digits": entry.digits,
+            }))
+            .collect();
+        println!("{}", serde_json::Value::from(listed));
+        return Ok(());
+    }
+
+    if vault.entries.is_empty() {
+        println!("No applications saved");
+        return Ok(());
+    }
+
+    let name_width = vault.entries.keys().map(|app| app.len()).max().unwrap_or(0).max("APPLICATION".len());
+    let issuer_width = vault.entries.values()
+        .map(|entry| entry.issuer.as_ref().map(|i| i.len()).unwrap_or(1))
+        .max().unwrap_or(0).max("ISSUER".len());
+
+    println!("{:<nw$}  {:<iw$}  {:<9}  {:<6}  DIGITS", "APPLICATION", "ISSUER", "ALGORITHM", "PERIOD", nw = name_width, iw = issuer_width);
+    for (app, entry) in vault.entries.iter() {
+        let issuer = entry.issuer.as_deref().unwrap_or("-");
+        let period = format!("{}s", entry.window);
+        println!("{:<nw$}  {:<iw$}  {:<9}  {:<6}  {}", app, issuer, entry.hash.name(), period, entry.digits, nw = name_width, iw = issuer_width);
+    }
+
+    Ok(())
+}
+
+fn remove_application(opts: &Opts, rm_opts: &RmOpts, storage_path: &Storage, config: &TwofaConfig, logger: &Logger) -> Result<(), TwofaError> {
+    let (_lock, key, mut vault) = load_storage(opts, storage_path, config, LockMode::Exclusive, logger)?;
+
+    vault.entry(&rm_opts.application)?;
+
+    if !rm_opts.force {
+        let question = format!("Remove '{}' ? Its codes can not be generated afterwards. [y/N] ", &rm_opts.application);
+        let user_prompt = prompt_for_input(&question[..])?;
+        if user_prompt.ne(&String::from("y")) {
+            return Err(TwofaError::Aborted);
+        }
+    }
+
+    vault.remove_entry(&rm_opts.application, SystemClock.now());
+
+    storage_backend(storage_path, config)?.delete_entry(&key, &vault, &rm_opts.application, logger)?;
+    record_history(storage_path, config, &format!("Remove {}", &rm_opts.application)[..], logger);
+
+    println!("Removed {}", &rm_opts.application);
+    Ok(())
+}
+
+fn rename_application(opts: &Opts, rename_opts: &RenameOpts, storage_path: &Storage, config: &TwofaConfig, logger: &Logger) -> Result<(), TwofaError> {
+    if rename_opts.old == rename_opts.new {
+        return Err(TwofaError::Other("Old and new name are the same"));
+    }
+
+    let (_lock, key, mut vault) = load_storage(opts, storage_path, config, LockMode::Exclusive, logger)?;
+
+    if vault.entries.contains_key(&rename_opts.new) {
+        return Err(TwofaError::Other("An application with the new name already exists"));
+    }
+
+    // a rename is a removal and an addition for copies merged later
+    let now = SystemClock.now();
+    match vault.remove_entry(&rename_opts.old, now) {
+        Some(entry) => {
+            vault.insert_entry(&rename_opts.new, entry, now);
         },
-        _ => {}
+        None => return Err(TwofaError::EntryNotFound(rename_opts.old.clone())),
+    }
+
+    storage_backend(storage_path, config)?.rename_entry(&key, &vault, &rename_opts.old, &rename_opts.new, logger)?;
+    record_history(storage_path, config, &format!("Rename {} to {}", &rename_opts.old, &rename_opts.new)[..], logger);
+
+    println!("Renamed {} to {}", &rename_opts.old, &rename_opts.new);
+    Ok(())
+}
+
+fn import_storage(opts: &Opts, import_opts: &ImportOpts, storage_path: &Storage, config: &TwofaConfig, logger: &Logger) -> Result<(), TwofaError> {
+    let file_data = match read_storage(&import_opts.file[..]) {
+        Ok(data) => SecretString::new(data),
+        Err(_) => return Err(TwofaError::Io("Could not read import file")),
     };
 
-    if let Err(_) = encrypt_file(&storage_path.de_file[..], &storage_path.en_file[..], &opts.password, &logger) {
-        println!("Could not encrypt file");
-        std::process::exit(1);
+    // validate everything before touching the storage
+    let mut imported = match Vault::from_json(file_data.expose()) {
+        Ok(vault) => vault,
+        Err(TwofaError::CorruptVault(msg)) => {
+            logger.norm("import_invalid", msg);
+            return Err(TwofaError::InvalidSecret("import file contains invalid applications"));
+        },
+        Err(e) => return Err(e),
     };
+    for (app, entry) in imported.entries.iter() {
+        if check_entry(entry).is_err() {
+            logger.norm("import_invalid", format!("Invalid secret for application '{}'", app).as_str());
+            return Err(TwofaError::InvalidSecret("import file contains invalid applications"));
+        }
+    }
+
+    let (_lock, key, mut vault) = load_storage(opts, storage_path, config, LockMode::Exclusive, logger)?;
 
-    if let Err(e) = delete_file(&storage_path.de_file[..], &logger) {
-        return Err(e);
+    let now = SystemClock.now();
+    let mut added = 0;
+    let mut skipped = 0;
+    let apps: Vec<String> = imported.entries.keys().cloned().collect();
+    for app in apps {
+        if vault.entries.contains_key(&app) && !import_opts.force {
+            logger.norm("import_skipped", format!("Skipping existing application '{}'", app).as_str());
+            skipped += 1;
+            continue;
+        }
+        if let Some(entry) = imported.entries.remove(&app) {
+            vault.insert_entry(&app, entry, now);
+            added += 1;
+        }
     }
+
+    write_storage(storage_path, &key, &vault, config, logger)?;
+    record_history(storage_path, config, &format!("Import {} applications from {}", added, &import_opts.file)[..], logger);
+
+    println!("Imported {} applications, skipped {}", added, skipped);
     Ok(())
 }
 
-fn get_code(opts: Opts, storage_path: Storage, logger: Logger) -> Result<(), &'static str>{
-    let app = opts.application.clone().unwrap();
+fn export_storage(opts: &Opts, export_opts: &ExportOpts, storage_path: &Storage, config: &TwofaConfig, logger: &Logger) -> Result<(), TwofaError> {
+    let (_lock, _key, vault) = load_storage(opts, storage_path, config, LockMode::Shared, logger)?;
 
-    if let Err(_) = decrypt_file(&storage_path.en_file[..], &storage_path.de_file[..], &opts.password, &logger) {
-        println!("Could not decrypt file");
-        std::process::exit(1);
-    };
+    let exported = vault.to_json_pretty()?;
 
-    let mut data_from_file: String = String::new();
-    let application_data: Option<Map<String, Value>>;
+    logger.norm("export_plaintext", "Warning: the export contains unencrypted secrets");
 
-    match read_storage(&storage_path.de_file[..]) {
-        Ok(data) => {
-            data_from_file.push_str(&data[..]);
+    match &export_opts.output {
+        Some(path) => {
+            if save_storage(&path[..], exported.expose()).is_err() {
+                return Err(TwofaError::Io("Could not write export file"));
+            }
         },
-        Err(e) => {
-            match e {
-                FileReadError::NoContent => { return Err("No content") },
-                FileReadError::NoFile => {
-                    if let Err(_) = save_storage(&storage_path.en_file[..], String::from("{}")) {
-                        logger.norm(
-                            format!("Could not save storage")
-                                .as_str()
-                        );
-                        std::process::exit(1);
-                    };
-
-                    data_from_file.push_str("{}");
+        None => {
+            println!("{}", exported.expose());
+        }
+    }
+
+    Ok(())
+}
+
+fn create_storage(opts: &Opts, storage_path: &Storage, config: &TwofaConfig, logger: &Logger) -> Result<(), TwofaError> {
+    if storage_path.dir != storage_path.home && !check_storage(&storage_path.home[..]) {
+        create_folder(&storage_path.home[..])?;
+    }
+    create_folder(&storage_path.dir[..])?;
+    let _lock = acquire_lock(&storage_path.lock[..], LockMode::Exclusive, config.lock.timeout, logger)?;
+
+    println!("Folderpath: {}", &storage_path.dir[..]);
+    let backend = storage_backend(storage_path, config)?;
+    println!("Storagepath Encrypted: {}", backend.path());
+
+    let key = actions::create_storage(&backend, || confirm("Storage already exist. Overwrite ? [y/N] "), || {
+        let password = match &opts.password {
+            Some(password) => SecretString::from(password.expose()),
+            None => {
+                let password = prompt_for_password("New password")?;
+                logger.add_secret(password.expose());
+                if password.ne(&prompt_for_password("Repeat password")?) {
+                    return Err(TwofaError::Other("Passwords do not match"));
                 }
-            };
-        },
-    };
+                password
+            }
+        };
+        Ok(derive_key(&password, logger)?)
+    }, logger)?;
 
-    logger.min(
-        format!("Data from file: \n {}", &data_from_file)
+    cache_key(&key, storage_path, config, logger);
+    record_history(storage_path, config, "Create storage", logger);
+
+    Ok(())
+}
+
+fn list_storage_backups(storage_path: &Storage) -> Result<(), TwofaError> {
+    let backups = list_backups(storage_path)?;
+
+    if backups.is_empty() {
+        println!("No backups saved");
+        return Ok(());
+    }
+
+    let id_width = backups.iter().map(|b| b.id.len()).max().unwrap_or(0).max("ID".len());
+
+    println!("{:<w$}  {:<20}  SIZE", "ID", "CREATED", w = id_width);
+    for backup in backups.iter() {
+        println!("{:<w$}  {:<20}  {}", backup.id, format_timestamp(backup.created), backup.size, w = id_width);
+    }
+
+    Ok(())
+}
+
+fn restore_storage_backup(opts: &Opts, restore_opts: &RestoreOpts, storage_path: &Storage, config: &TwofaConfig, logger: &Logger) -> Result<(), TwofaError> {
+    let _lock = acquire_lock(&storage_path.lock[..], LockMode::Exclusive, config.lock.timeout, logger)?;
+    let backup = find_backup(storage_path, &restore_opts.id)?;
+
+    // the backup has to open with the current password before it replaces anything
+    let backup_backend = backend_for_file(&backup.path[..], storage_path);
+    let (key, vault, _legacy) = unlock_storage(opts, storage_path, config, &*backup_backend, logger)?;
+
+    // keep the current storage, so the restore can be undone. The backup
+    // may have been written with another key than the storage
+    storage_backend(storage_path, config)?.replace(&key, &vault, logger)?;
+    record_history(storage_path, config, &format!("Restore backup {}", &backup.id)[..], logger);
+
+    println!("Restored backup {} with {} applications", &backup.id, vault.entries.len());
+    Ok(())
+}
+
+/// Commits the storage to its history if enabled. The change itself is
+/// already saved, so failing to record it is only warned about.
+fn record_history(storage_path: &Storage, config: &TwofaConfig, message: &str, logger: &Logger) {
+    if !config.history.enabled {
+        return;
+    }
+
+    if let Err(e) = record_change(storage_path, message, logger) {
+        logger.norm(
+            "history_failed",
+            format!("Warning: could not record '{}' in the history: {}", message, e)
+                .as_str()
+        );
+    }
+}
+
+fn list_storage_history(storage_path: &Storage, logger: &Logger) -> Result<(), TwofaError> {
+    let revisions = list_history(storage_path, logger)?;
+
+    if revisions.is_empty() {
+        println!("No history recorded. Enable it with 'twofa config set history.enabled true'");
+        return Ok(());
+    }
+
+    let id_width = revisions.iter().map(|r| r.id.len()).max().unwrap_or(0).max("REVISION".len());
+
+    println!("{:<w$}  {:<20}  CHANGE", "REVISION", "CREATED", w = id_width);
+    for revision in revisions.iter() {
+        println!("{:<w$}  {:<20}  {}", revision.id, format_timestamp(revision.created), revision.message, w = id_width);
+    }
+
+    Ok(())
+}
+
+fn restore_history_revision(opts: &Opts, restore_opts: &HistoryRestoreOpts, storage_path: &Storage, config: &TwofaConfig, logger: &Logger) -> Result<(), TwofaError> {
+    let _lock = acquire_lock(&storage_path.lock[..], LockMode::Exclusive, config.lock.timeout, logger)?;
+
+    let tmp = format!("{}/history.{}.tmp", &storage_path.dir, std::process::id());
+    checkout_revision(storage_path, &restore_opts.rev, &tmp[..], logger)?;
+
+    // like a backup, the revision has to open before it replaces anything
+    let revision_backend = backend_for_file(&tmp[..], storage_path);
+    let unlocked = unlock_storage(opts, storage_path, config, &*revision_backend, logger);
+    delete_file(&tmp[..], logger)?;
+    let (key, vault, _legacy) = unlocked?;
+
+    storage_backend(storage_path, config)?.replace(&key, &vault, logger)?;
+    if has_history(storage_path) {
+        if let Err(e) = record_change(storage_path, &format!("Restore revision {}", &restore_opts.rev)[..], logger) {
+            logger.norm(
+                "history_failed",
+                format!("Warning: could not record the restore in the history: {}", e)
+                    .as_str()
+            );
+        }
+    }
+
+    println!("Restored revision {} with {} applications", &restore_opts.rev, vault.entries.len());
+    Ok(())
+}
+
+/// Moves the storage to another backend and keeps it there by setting
+/// `storage.backend` in the config of the storage.
+fn migrate_backend(opts: &Opts, migrate_opts: &MigrateBackendOpts, storage_path: &Storage, config: &TwofaConfig, logger: &Logger) -> Result<(), TwofaError> {
+    let kind = BackendKind::from_name(&migrate_opts.backend[..])?;
+    let current = open_backend(storage_path, config)?;
+    if !current.exists() {
+        return Err(TwofaError::VaultNotFound);
+    }
+
+    let path = storage_path.profile_config.as_ref().unwrap_or(&storage_path.config);
+    set_config_value(&path[..], "storage.backend", kind.name())?;
+    if current.kind() == kind {
+        println!("Storage already uses the {} backend", kind.name());
+        return Ok(());
+    }
+
+    // unlocking moves the storage to the backend the config now names
+    let config = read_config(&storage_path.config[..], storage_path.profile_config.as_deref())?;
+    let (_lock, _key, vault) = load_storage(opts, storage_path, &config, LockMode::Exclusive, logger)?;
+
+    println!("Moved {} applications from the {} to the {} backend", vault.entries.len(), current.kind().name(), kind.name());
+    Ok(())
+}
+
+/// Moves the unlocked storage to the `kind` backend and records it.
+fn move_storage(storage_path: &Storage, config: &TwofaConfig, key: &VaultKey, vault: &Vault, kind: BackendKind, logger: &Logger) -> Result<(), TwofaError> {
+    let current = open_backend(storage_path, config)?;
+    let target = move_to_backend(&*current, kind, storage_path, key, vault, &config.backup, &SystemClock, logger)?;
+
+    record_history(storage_path, config, &format!("Move storage to the {} backend", kind.name())[..], logger);
+    logger.norm(
+        "backend_migrated",
+        format!("Moved storage from '{}' to '{}'", current.path(), target.path())
             .as_str()
     );
 
-    let deserialized_data: SerdeResult<Value> = from_str(data_from_file.as_str());
+    Ok(())
+}
 
-    if let SerdeResult::Err(_) = deserialized_data {
-        return Err("Could not parse storage from file");
+/// Merges another copy of the storage into this one. The other copy is
+/// left in place and can be removed once the result looks right.
+fn merge_storage(opts: &Opts, merge_opts: &MergeOpts, storage_path: &Storage, config: &TwofaConfig, logger: &Logger) -> Result<(), TwofaError> {
+    if !check_storage(&merge_opts.other[..]) {
+        return Err(TwofaError::Io("Could not read the storage to merge"));
+    }
+    if merge_opts.prefer.is_none() && !is_interactive() {
+        return Err(TwofaError::Other("Conflicts can only be resolved interactively, use --prefer to merge without asking"));
     }
 
-    match deserialized_data {
-        SerdeResult::Ok(data) => {
-            match data[&app.as_str()].clone() {
-                Value::Object(obj) => {
-                  application_data = Some(obj);
-                },
-                _ => {
-                    println!("Application does not exist. Exiting.");
-                    std::process::exit(0);
-                }
-            };
+    // before asking for the password; either file, as unlocking may move the storage
+    let other_backend = backend_for_foreign_file(&merge_opts.other[..], storage_path);
+    let other_path = std::fs::canonicalize(other_backend.path()).ok();
+    if [&storage_path.en_file, &storage_path.db_file].iter().any(|file| std::fs::canonicalize(file).ok() == other_path) {
+        return Err(TwofaError::Other("Can not merge the storage with itself"));
+    }
+
+    let (_lock, key, mut vault) = load_storage(opts, storage_path, config, LockMode::Exclusive, logger)?;
+    let (_other_key, other) = unlock_foreign(opts, &*other_backend, Some(&key), logger)?;
+
+    let summary = merge_vaults(&mut vault, other, SystemClock.now(), |conflict| {
+        resolve_conflict(conflict, merge_opts.prefer.as_deref())
+    })?;
+
+    write_storage(storage_path, &key, &vault, config, logger)?;
+    record_history(storage_path, config, &format!("Merge {}", &merge_opts.other)[..], logger);
+
+    println!(
+        "Merged {}: {} added, {} updated, {} removed, {} conflicts resolved",
+        &merge_opts.other, summary.added, summary.updated, summary.removed, summary.conflicts
+    );
+    Ok(())
+}
+
+fn describe_state(state: &State) -> String {
+    let changed = |time: u64| if time == 0 { String::from("at an unknown time") } else { format_timestamp(time) };
+    match state {
+        State::Present(entry) => format!(
+            "changed {}: {}, {} digits every {}s{}",
+            changed(entry.modified), entry.hash.name(), entry.digits, entry.window,
+            entry.issuer.as_ref().map(|issuer| format!(", issued by {}", issuer)).unwrap_or_default()
+        ),
+        State::Deleted(time) => format!("removed {}", changed(*time)),
+        State::Missing => String::from("not present"),
+    }
+}
+
+/// Picks a side as `prefer` says, or asks which one to keep.
+fn resolve_conflict(conflict: &Conflict, prefer: Option<&str>) -> Result<Side, TwofaError> {
+    match prefer {
+        Some("ours") => return Ok(Side::Ours),
+        Some("theirs") => return Ok(Side::Theirs),
+        Some(_) => {
+            let newer = if conflict.theirs.changed() > conflict.ours.changed() { Side::Theirs } else { Side::Ours };
+            return Ok(newer);
         },
-        SerdeResult::Err(_) => {
-            return Err("Error accessing deserialized data");
+        None => {},
+    }
+
+    println!("'{}' was changed in both copies", conflict.app);
+    println!("  ours:   {}", describe_state(conflict.ours));
+    println!("  theirs: {}", describe_state(conflict.theirs));
+    if let (State::Present(ours), State::Present(theirs)) = (conflict.ours, conflict.theirs) {
+        if ours.secret.expose() != theirs.secret.expose() {
+            println!("  the secrets differ");
         }
+    }
+
+    match &prompt_for_input("Keep [o]urs or [t]heirs ? [o/t]")?[..] {
+        "o" => Ok(Side::Ours),
+        "t" => Ok(Side::Theirs),
+        _ => Err(TwofaError::Aborted),
+    }
+}
+
+/// Opens one side of a diff: the id of a backup, an export or an
+/// encrypted storage. Encrypted copies are opened with `known`, which is
+/// set to the key that opened them, or the password.
+fn open_compared(opts: &Opts, location: &str, storage_path: &Storage, known: &mut Option<VaultKey>, logger: &Logger) -> Result<Vault, TwofaError> {
+    let path = match find_backup(storage_path, location) {
+        Ok(backup) => backup.path,
+        Err(_) => location.to_owned(),
     };
+    if !check_storage(&path[..]) {
+        logger.norm("diff_missing", format!("'{}' is neither a file nor a backup", location).as_str());
+        return Err(TwofaError::Io("Could not read storage to compare"));
+    }
 
-    let twofa_settings = create_twofa_settings(application_data).expect("Could not create TwofaSettings");
+    open_copy(&path[..], || {
+        let backend = backend_for_foreign_file(&path[..], storage_path);
+        let (key, vault) = unlock_foreign(opts, &*backend, known.as_ref(), logger)?;
+        *known = Some(key);
+        Ok(vault)
+    })
+}
 
-    logger.min(
-        format!("Created TwofaSettings: \n {}", &twofa_settings.to_string())
-            .as_str()
-    );
+fn format_field(value: &serde_json::Value) -> String {
+    match value {
+        serde_json::Value::Null => String::from("(none)"),
+        serde_json::Value::String(s) => s.clone(),
+        value => value.to_string(),
+    }
+}
+
+fn format_summary(summary: &serde_json::Value) -> String {
+    summary.as_object()
+        .map(|fields| fields.iter()
+            .filter(|(field, _)| *field != "application")
+            .map(|(field, value)| format!("{} {}", field, format_field(value)))
+            .collect::<Vec<String>>()
+            .join(", "))
+        .unwrap_or_default()
+}
+
+fn diff_storages(opts: &Opts, diff_opts: &DiffOpts, storage_path: &Storage, config: &TwofaConfig, logger: &Logger) -> Result<(), TwofaError> {
+    let _lock = acquire_lock(&storage_path.lock[..], LockMode::Shared, config.lock.timeout, logger)?;
+    let mut key = None;
+    let a = open_compared(opts, &diff_opts.a[..], storage_path, &mut key, logger)?;
+    let b = open_compared(opts, &diff_opts.b[..], storage_path, &mut key, logger)?;
 
-    let code = create_code_with_twofa_settings(&twofa_settings).expect("Could not get code with settings");
+    let diff = diff_vaults(&a, &b);
 
-    if &opts.copy > &0 {
-        let mut ctx: ClipboardContext = ClipboardProvider::new().unwrap();
-        if let Err(_) = ctx.set_contents(code.clone()) {
-            println!("Could not copy code to clipboard");
+    if output_json(opts, config) {
+        match serde_json::to_string(&diff) {
+            Ok(json) => println!("{}", json),
+            Err(_) => return Err(TwofaError::Other("Could not serialize differences")),
         }
+        return Ok(());
     }
 
-    println!("Code: {}", code);
+    if diff.is_empty() {
+        println!("No differences");
+        return Ok(());
+    }
 
-    encrypt_file(&storage_path.de_file[..], &storage_path.en_file[..], &opts.password, &logger).expect("Could not encrypt file");
+    for added in diff.added.iter() {
+        println!("+ {}: {}", format_field(&added["application"]), format_summary(added));
+    }
+    for removed in diff.removed.iter() {
+        println!("- {}: {}", format_field(&removed["application"]), format_summary(removed));
+    }
+    for modified in diff.modified.iter() {
+        println!("~ {}", &modified.application);
+        for change in modified.changes.iter() {
+            println!("    {}: {} -> {}", change.field, format_field(&change.from), format_field(&change.to));
+        }
+    }
+    println!("{} added, {} removed, {} modified", diff.added.len(), diff.removed.len(), diff.modified.len());
 
-    if let Err(e) = delete_file(&storage_path.de_file[..], &logger) {
-        return Err(e);
+    Ok(())
+}
+
+fn list_storage_profiles(storage_path: &Storage) -> Result<(), TwofaError> {
+    let profiles = list_profiles(&storage_path.home[..])?;
+    let width = profiles.iter().map(|name| name.len()).max().unwrap_or(0);
+
+    for name in profiles.iter() {
+        let active = if storage_path.profile.as_deref() == Some(&name[..]) { "*" } else { " " };
+        let profile_path = get_storage_path(storage_path.home.clone(), profile_dir(&storage_path.home[..], &name[..]), Some(name.clone()));
+        let state = if check_storage(&profile_path.en_file[..]) || check_storage(&profile_path.db_file[..]) { "" } else { "  (not initialized)" };
+        println!("{} {:<w$}  {}{}", active, name, &profile_path.dir, state, w = width);
     }
+
     Ok(())
 }
 
-fn create_storage(opts: Opts, storage_path: Storage, logger: Logger) -> Result<(), &'static str> {
-    create_folder(&storage_path.dir[..]);
+fn create_profile(opts: &Opts, create_opts: &ProfileCreateOpts, storage_path: &Storage, logger: &Logger) -> Result<(), TwofaError> {
+    let dir = new_profile_dir(&storage_path.home[..], &create_opts.name[..])?;
+    let profile_path = get_storage_path(storage_path.home.clone(), dir, Some(create_opts.name.clone()));
+    let config = read_config(&profile_path.config[..], profile_path.profile_config.as_deref())?;
+    create_storage(opts, &profile_path, &config, logger)?;
 
-    println!("Folderpath: {}", &storage_path.dir[..]);
-    println!("Storagepath Decrypted: {}", &storage_path.de_file[..]);
-    println!("Storagepath Encrypted: {}", &storage_path.en_file[..]);
+    println!("Created profile '{}'. Use it with --profile {} or {}={}", &create_opts.name, &create_opts.name, PROFILE_VAR, &create_opts.name);
+    Ok(())
+}
+
+fn delete_profile(delete_opts: &ProfileDeleteOpts, storage_path: &Storage, config: &TwofaConfig, logger: &Logger) -> Result<(), TwofaError> {
+    let dir = deletable_profile_dir(&storage_path.home[..], &delete_opts.name[..])?;
 
-    if check_storage(&storage_path.en_file[..]) {
-        let user_prompt = prompt_for_input("Storage already exist. Overwrite ? [y/N] ").unwrap();
+    if !delete_opts.force {
+        let user_prompt = prompt_for_input(format!("Delete profile '{}' with all its applications and backups ? [y/N] ", &delete_opts.name).as_str())?;
         if user_prompt.ne(&String::from("y")) {
-            println!("Stopping action");
-            std::process::exit(0);
-        }
-        if let Err(_) = save_storage(&storage_path.de_file[..], String::from("{}")) {
-            return Err("Could not overwrite file");
-        }
-    } else {
-        if let Err(_) = save_storage(&storage_path.de_file[..], String::from("{}")) {
-            println!("Could not save storage");
-            std::process::exit(1);
+            return Err(TwofaError::Aborted);
         }
     }
 
+    let profile_path = get_storage_path(storage_path.home.clone(), dir, Some(delete_opts.name.clone()));
+    let lock = acquire_lock(&profile_path.lock[..], LockMode::Exclusive, config.lock.timeout, logger)?;
+
+    if let Ok(keyring) = Keyring::from_name(&config.key_cache.keyring[..]) {
+        revoke_key(&key_description(&profile_path)[..], &keyring, logger);
+    }
+    if remove_dir_all(&profile_path.dir).is_err() {
+        return Err(TwofaError::Io("Could not delete profile directory"));
+    }
+    drop(lock);
+
+    if config.default_profile == delete_opts.name {
+        logger.norm("default_profile_deleted", "Warning: default_profile in config.toml still names the deleted profile");
+    }
+
+    println!("Deleted profile '{}'", &delete_opts.name);
+    Ok(())
+}
 
-    if let Err(e) = encrypt_file(&storage_path.de_file[..], &storage_path.en_file[..], &opts.password, &logger) {
-        return Err(e);
+fn list_config(config: &TwofaConfig) -> Result<(), TwofaError> {
+    for (key, value) in config_entries(config)? {
+        println!("{} = {}", key, value);
     }
 
-    if let Err(e) = delete_file(&storage_path.de_file[..], &logger) {
-        return Err(e);
+    Ok(())
+}
+
+fn get_config(get_opts: &ConfigGetOpts, config: &TwofaConfig) -> Result<(), TwofaError> {
+    match config_entries(config)?.into_iter().find(|(key, _)| key == &get_opts.key) {
+        Some((_, toml::Value::String(value))) => println!("{}", value),
+        Some((_, value)) => println!("{}", value),
+        None => return Err(TwofaError::Other("Unknown config key. See 'twofa config list'")),
     }
 
     Ok(())
 }
+
+fn set_config(set_opts: &ConfigSetOpts, storage_path: &Storage) -> Result<(), TwofaError> {
+    let path = match (&storage_path.profile_config, set_opts.local) {
+        (Some(path), true) => path,
+        (None, true) => return Err(TwofaError::Other("The storage in use has no config of its own, leave out --local")),
+        (_, false) => &storage_path.config,
+    };
+
+    set_config_value(&path[..], &set_opts.key[..], &set_opts.value[..])?;
+    println!("Set {} in '{}'", &set_opts.key, path);
+
+    Ok(())
+}
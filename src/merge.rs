@@ -0,0 +1,313 @@
+use std::collections::{BTreeMap, BTreeSet};
+use crate::error::TwofaError;
+use crate::vault::{Entry, Vault};
+
+// Merges copies of a storage that were changed apart, e.g. on two machines
+// syncing the same file. The last merge both copies have seen serves as the
+// common base: an application changed since then on one side only takes
+// that change, one changed on both sides is a conflict. Removals both copies
+// already knew at that merge have nothing left to tell and are dropped.
+
+/// An application in one copy.
+pub enum State {
+    Present(Entry),
+    /// removed at the given time
+    Deleted(u64),
+    /// never known to this copy
+    Missing,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Side {
+    Ours,
+    Theirs,
+}
+
+/// An application both copies changed since their last merge.
+pub struct Conflict<'a> {
+    pub app: &'a str,
+    pub ours: &'a State,
+    pub theirs: &'a State,
+}
+
+#[derive(Default, Debug, PartialEq)]
+pub struct MergeSummary {
+    pub added: usize,
+    pub updated: usize,
+    pub removed: usize,
+    pub conflicts: usize,
+}
+
+impl State {
+    fn take(entries: &mut BTreeMap<String, Entry>, deleted: &BTreeMap<String, u64>, app: &str) -> Self {
+        match (entries.remove(app), deleted.get(app)) {
+            (Some(entry), _) => State::Present(entry),
+            (None, Some(time)) => State::Deleted(*time),
+            (None, None) => State::Missing,
+        }
+    }
+
+    /// seconds since epoch of the last change, 0 if unknown
+    pub fn changed(&self) -> u64 {
+        match self {
+            State::Present(entry) => entry.modified,
+            State::Deleted(time) => *time,
+            State::Missing => 0,
+        }
+    }
+
+    fn same(&self, other: &State) -> bool {
+        match (self, other) {
+            (State::Present(a), State::Present(b)) => a.same_settings(b),
+            (State::Present(_), _) | (_, State::Present(_)) => false,
+            _ => true,
+        }
+    }
+}
+
+enum Decision {
+    Take(Side),
+    Conflict,
+}
+
+fn decide(ours: &State, theirs: &State, base: u64) -> Decision {
+    if ours.same(theirs) {
+        let newer = if theirs.changed() > ours.changed() { Side::Theirs } else { Side::Ours };
+        return Decision::Take(newer);
+    }
+
+    // a copy that never knew the application can not have removed it
+    match (ours, theirs) {
+        (State::Missing, _) => return Decision::Take(Side::Theirs),
+        (_, State::Missing) => return Decision::Take(Side::Ours),
+        _ => {},
+    }
+
+    match (ours.changed() > base, theirs.changed() > base) {
+        (true, false) => Decision::Take(Side::Ours),
+        (false, true) => Decision::Take(Side::Theirs),
+        // changed on both sides, or differing without either knowing why
+        _ => Decision::Conflict,
+    }
+}
+
+/// Merges `theirs` into `ours` and marks `ours` as merged at `now`.
+/// `resolve` picks a side for every conflict; if it fails, `ours` is left
+/// incomplete and must not be saved.
+pub fn merge_vaults(ours: &mut Vault, mut theirs: Vault, now: u64, mut resolve: impl FnMut(&Conflict) -> Result<Side, TwofaError>) -> Result<MergeSummary, TwofaError> {
+    let base = ours.merged.min(theirs.merged);
+    let mut our_entries = std::mem::take(&mut ours.entries);
+    let our_deleted = std::mem::take(&mut ours.deleted);
+    let mut their_entries = std::mem::take(&mut theirs.entries);
+    let their_deleted = std::mem::take(&mut theirs.deleted);
+
+    let apps: BTreeSet<String> = our_entries.keys()
+        .chain(our_deleted.keys())
+        .chain(their_entries.keys())
+        .chain(their_deleted.keys())
+        .cloned()
+        .collect();
+
+    let mut summary = MergeSummary::default();
+    for app in apps {
+        let mine = State::take(&mut our_entries, &our_deleted, &app);
+        let other = State::take(&mut their_entries, &their_deleted, &app);
+
+        let side = match decide(&mine, &other, base) {
+            Decision::Take(side) => side,
+            Decision::Conflict => {
+                summary.conflicts += 1;
+                resolve(&Conflict { app: &app, ours: &mine, theirs: &other })?
+            },
+        };
+
+        let seen_by_both = matches!((&mine, &other), (State::Deleted(a), State::Deleted(b)) if *a < base && *b < base);
+
+        let kept = match side {
+            Side::Ours => mine,
+            Side::Theirs => {
+                match (&mine, &other) {
+                    _ if mine.same(&other) => {},
+                    (State::Present(_), State::Present(_)) => summary.updated += 1,
+                    (_, State::Present(_)) => summary.added += 1,
+                    (State::Present(_), _) => summary.removed += 1,
+                    _ => {},
+                }
+                other
+            },
+        };
+
+        match kept {
+            State::Present(entry) => {
+                ours.entries.insert(app, entry);
+            },
+            State::Deleted(_) if seen_by_both => {},
+            State::Deleted(time) => {
+                ours.deleted.insert(app, time);
+            },
+            State::Missing => {},
+        }
+    }
+
+    ours.merged = now;
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::DefaultsConfig;
+    use crate::secret::SecretString;
+
+    const MERGED: u64 = 1000;
+
+    fn entry(secret: &str, modified: u64) -> Entry {
+        let mut entry = Entry::new(SecretString::from(secret), &DefaultsConfig::default());
+        entry.modified = modified;
+        entry
+    }
+
+    /// A copy last merged at `MERGED` with `github` added before that.
+    fn synced() -> Vault {
+        let mut vault = Vault::new();
+        vault.entries.insert(String::from("github"), entry("JBSWY3DPEHPK3PXP", 500));
+        vault.merged = MERGED;
+        vault
+    }
+
+    fn no_conflicts(conflict: &Conflict) -> Result<Side, TwofaError> {
+        panic!("unexpected conflict for {}", conflict.app);
+    }
+
+    fn secret(vault: &Vault, app: &str) -> String {
+        vault.entry(app).unwrap().secret.expose().to_owned()
+    }
+
+    #[test]
+    fn takes_changes_made_on_one_side() {
+        let mut ours = synced();
+        ours.insert_entry("aws", entry("AAAAAAAA", 0), 1100);
+        let mut theirs = synced();
+        theirs.insert_entry("github", entry("GEZDGNBV", 0), 1200);
+
+        let summary = merge_vaults(&mut ours, theirs, 2000, no_conflicts).unwrap();
+
+        assert_eq!(summary, MergeSummary { added: 0, updated: 1, removed: 0, conflicts: 0 });
+        assert_eq!(secret(&ours, "github"), "GEZDGNBV");
+        assert_eq!(secret(&ours, "aws"), "AAAAAAAA");
+        assert_eq!(ours.merged, 2000);
+    }
+
+    #[test]
+    fn adds_applications_only_the_other_copy_has() {
+        let mut ours = synced();
+        let mut theirs = synced();
+        theirs.insert_entry("aws", entry("AAAAAAAA", 0), 1100);
+
+        let summary = merge_vaults(&mut ours, theirs, 2000, no_conflicts).unwrap();
+
+        assert_eq!(summary.added, 1);
+        assert_eq!(secret(&ours, "aws"), "AAAAAAAA");
+    }
+
+    #[test]
+    fn removes_applications_removed_on_the_other_side() {
+        let mut ours = synced();
+        let mut theirs = synced();
+        theirs.remove_entry("github", 1100);
+
+        let summary = merge_vaults(&mut ours, theirs, 2000, no_conflicts).unwrap();
+
+        assert_eq!(summary.removed, 1);
+        assert!(ours.entries.is_empty());
+        assert_eq!(ours.deleted.get("github"), Some(&1100));
+    }
+
+    #[test]
+    fn drops_removals_both_copies_saw_before_their_last_merge() {
+        let mut ours = synced();
+        ours.remove_entry("github", 800);
+        ours.deleted.insert(String::from("gitlab"), 1100);
+        ours.deleted.insert(String::from("bitbucket"), 600);
+        let mut theirs = synced();
+        theirs.remove_entry("github", 800);
+
+        merge_vaults(&mut ours, theirs, 2000, no_conflicts).unwrap();
+
+        assert!(!ours.deleted.contains_key("github"));
+        assert_eq!(ours.deleted.get("gitlab"), Some(&1100));
+        // the other copy may not have seen it yet
+        assert_eq!(ours.deleted.get("bitbucket"), Some(&600));
+    }
+
+    #[test]
+    fn keeps_applications_removed_before_they_were_changed() {
+        let mut ours = synced();
+        ours.remove_entry("github", 1100);
+        let mut theirs = synced();
+        theirs.remove_entry("github", 1100);
+        theirs.insert_entry("github", entry("GEZDGNBV", 0), 1200);
+
+        merge_vaults(&mut ours, theirs, 2000, |_| Ok(Side::Theirs)).unwrap();
+
+        assert_eq!(secret(&ours, "github"), "GEZDGNBV");
+        assert!(!ours.deleted.contains_key("github"));
+    }
+
+    #[test]
+    fn asks_for_applications_changed_on_both_sides() {
+        let mut ours = synced();
+        ours.insert_entry("github", entry("GEZDGNBV", 0), 1100);
+        let mut theirs = synced();
+        theirs.remove_entry("github", 1200);
+
+        let mut asked = Vec::new();
+        let summary = merge_vaults(&mut ours, theirs, 2000, |conflict| {
+            asked.push(conflict.app.to_owned());
+            assert!(matches!(conflict.theirs, State::Deleted(1200)));
+            Ok(Side::Ours)
+        }).unwrap();
+
+        assert_eq!(asked, vec!["github"]);
+        assert_eq!(summary.conflicts, 1);
+        assert_eq!(secret(&ours, "github"), "GEZDGNBV");
+    }
+
+    #[test]
+    fn does_not_ask_for_identical_changes() {
+        let mut ours = synced();
+        ours.insert_entry("github", entry("GEZDGNBV", 0), 1100);
+        let mut theirs = synced();
+        theirs.insert_entry("github", entry("GEZDGNBV", 0), 1200);
+
+        let summary = merge_vaults(&mut ours, theirs, 2000, no_conflicts).unwrap();
+
+        assert_eq!(summary, MergeSummary::default());
+        assert_eq!(ours.entry("github").unwrap().modified, 1200);
+    }
+
+    #[test]
+    fn asks_for_differences_in_copies_never_merged() {
+        let mut ours = Vault::new();
+        ours.entries.insert(String::from("github"), entry("JBSWY3DPEHPK3PXP", 0));
+        let mut theirs = Vault::new();
+        theirs.entries.insert(String::from("github"), entry("GEZDGNBV", 0));
+
+        let summary = merge_vaults(&mut ours, theirs, 2000, |_| Ok(Side::Theirs)).unwrap();
+
+        assert_eq!(summary, MergeSummary { added: 0, updated: 1, removed: 0, conflicts: 1 });
+        assert_eq!(secret(&ours, "github"), "GEZDGNBV");
+    }
+
+    #[test]
+    fn stops_when_a_conflict_is_not_resolved() {
+        let mut ours = synced();
+        ours.insert_entry("github", entry("GEZDGNBV", 0), 1100);
+        let mut theirs = synced();
+        theirs.insert_entry("github", entry("MFRGGZDF", 0), 1200);
+
+        let result = merge_vaults(&mut ours, theirs, 2000, |_| Err(TwofaError::Aborted));
+
+        assert!(matches!(result, Err(TwofaError::Aborted)));
+    }
+}
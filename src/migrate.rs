@@ -0,0 +1,211 @@
+use std::convert::TryFrom;
+use serde_json::Value;
+use crate::error::TwofaError;
+use crate::helper::wipe_json;
+
+type Migration = fn(Value) -> Result<Value, TwofaError>;
+
+/// Each migration upgrades storage content from the version at its index
+/// to the next one. Append new migrations, never change released ones.
+const MIGRATIONS: &[Migration] = &[
+    v0_to_v1,
+    v1_to_v2,
+    v2_to_v3,
+];
+
+pub const SCHEMA_VERSION: u32 = MIGRATIONS.len() as u32;
+
+/// Storages written before the schema was versioned have no `version`.
+pub fn schema_version(raw: &Value) -> u32 {
+    match raw["version"].as_u64() {
+        Some(version) => u32::try_from(version).unwrap_or(u32::MAX),
+        None => 0,
+    }
+}
+
+/// Upgrades `raw` step by step to `SCHEMA_VERSION` and returns it together
+/// with the version it was stored with.
+pub fn migrate(mut raw: Value) -> Result<(Value, u32), TwofaError> {
+    if !raw.is_object() {
+        wipe_json(&mut raw);
+        return Err(TwofaError::CorruptVault("storage does not contain applications"));
+    }
+
+    let from = schema_version(&raw);
+    if from > SCHEMA_VERSION {
+        wipe_json(&mut raw);
+        return Err(TwofaError::UnsupportedVersion(from));
+    }
+
+    for (version, migration) in MIGRATIONS.iter().enumerate().skip(from as usize) {
+        raw = migration(raw)?;
+        raw["version"] = Value::from(version as u32 + 1);
+    }
+
+    Ok((raw, from))
+}
+
+/// `{app: {secret, window, hash, encoding}}` to `{version, entries: {app: ...}}`
+fn v0_to_v1(raw: Value) -> Result<Value, TwofaError> {
+    Ok(serde_json::json!({
+        "entries": raw,
+    }))
+}
+
+/// Writes out the settings that were implied when missing and adds
+/// `digits`. New applications take their defaults from the config instead.
+fn v1_to_v2(mut raw: Value) -> Result<Value, TwofaError> {
+    let implied = [
+        ("window", Value::from(30)),
+        ("hash", Value::from("sha512")),
+        ("encoding", Value::from("base32")),
+        ("digits", Value::from(6)),
+    ];
+
+    if let Some(entries) = raw["entries"].as_object_mut() {
+        for entry in entries.values_mut().filter_map(|entry| entry.as_object_mut()) {
+            for (field, value) in implied.iter() {
+                entry.entry(*field).or_insert_with(|| value.clone());
+            }
+        }
+    }
+
+    Ok(raw)
+}
+
+/// Adds the change times `merge` decides by. Existing applications count
+/// as never changed, which a merge treats as older than any change.
+fn v2_to_v3(mut raw: Value) -> Result<Value, TwofaError> {
+    if let Some(entries) = raw["entries"].as_object_mut() {
+        for entry in entries.values_mut().filter_map(|entry| entry.as_object_mut()) {
+            entry.entry("modified").or_insert_with(|| Value::from(0));
+        }
+    }
+
+    if let Some(vault) = raw.as_object_mut() {
+        vault.entry("deleted").or_insert_with(|| serde_json::json!({}));
+        vault.entry("merged").or_insert_with(|| Value::from(0));
+    }
+
+    Ok(raw)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::twofa::{Encoding, Hash};
+    use crate::vault::Vault;
+
+    const FIXTURES: &[(u32, &str)] = &[
+        (0, include_str!("../tests/fixtures/vault_v0.json")),
+        (1, include_str!("../tests/fixtures/vault_v1.json")),
+        (2, include_str!("../tests/fixtures/vault_v2.json")),
+        (3, include_str!("../tests/fixtures/vault_v3.json")),
+    ];
+
+    #[test]
+    fn has_a_fixture_for_every_version() {
+        for version in 0..=SCHEMA_VERSION {
+            assert!(FIXTURES.iter().any(|(v, _)| *v == version), "no fixture for version {}", version);
+        }
+    }
+
+    #[test]
+    fn detects_version_of_fixtures() {
+        for (version, data) in FIXTURES {
+            let raw: Value = serde_json::from_str(data).unwrap();
+            assert_eq!(schema_version(&raw), *version);
+        }
+    }
+
+    #[test]
+    fn migrates_every_fixture_to_current_version() {
+        for (version, data) in FIXTURES {
+            let raw: Value = serde_json::from_str(data).unwrap();
+            let (migrated, from) = migrate(raw).unwrap();
+
+            assert_eq!(from, *version);
+            assert_eq!(schema_version(&migrated), SCHEMA_VERSION);
+
+            let vault = Vault::from_json(&migrated.to_string()).unwrap();
+            assert_eq!(vault.migrated_from, None);
+
+            let github = vault.entry("github").unwrap();
+            assert_eq!(github.secret.expose(), "JBSWY3DPEHPK3PXP");
+            assert_eq!(github.window, 30);
+            assert_eq!(github.hash, Hash::Sha1);
+            assert_eq!(github.encoding, Encoding::Base32);
+            assert_eq!(github.digits, 6);
+
+            let aws = vault.entry("aws").unwrap();
+            assert_eq!(aws.window, 60);
+            assert_eq!(aws.hash, Hash::Sha256);
+            assert_eq!(aws.encoding, Encoding::Hex);
+        }
+    }
+
+    #[test]
+    fn reports_version_the_vault_was_loaded_from() {
+        let (_, data) = FIXTURES[0];
+        let vault = Vault::from_json(data).unwrap();
+        assert_eq!(vault.migrated_from, Some(0));
+        assert_eq!(vault.version, SCHEMA_VERSION);
+    }
+
+    #[test]
+    fn keeps_application_named_version_in_v0() {
+        let raw = serde_json::json!({
+            "version": { "secret": "JBSWY3DPEHPK3PXP" },
+        });
+        let (migrated, from) = migrate(raw).unwrap();
+
+        assert_eq!(from, 0);
+        assert_eq!(migrated["entries"]["version"]["secret"], "JBSWY3DPEHPK3PXP");
+    }
+
+    #[test]
+    fn writes_out_settings_implied_by_v1() {
+        let raw = serde_json::json!({
+            "version": 1,
+            "entries": { "github": { "secret": "JBSWY3DPEHPK3PXP" } },
+        });
+        let (migrated, _) = migrate(raw).unwrap();
+
+        let github = &migrated["entries"]["github"];
+        assert_eq!(github["hash"], "sha512");
+        assert_eq!(github["window"], 30);
+        assert_eq!(github["encoding"], "base32");
+        assert_eq!(github["digits"], 6);
+    }
+
+    #[test]
+    fn adds_change_times_to_v2() {
+        let raw = serde_json::json!({
+            "version": 2,
+            "entries": { "github": { "secret": "JBSWY3DPEHPK3PXP", "window": 30, "hash": "sha1", "encoding": "base32", "digits": 6 } },
+        });
+        let (migrated, _) = migrate(raw).unwrap();
+
+        assert_eq!(migrated["entries"]["github"]["modified"], 0);
+        assert_eq!(migrated["deleted"], serde_json::json!({}));
+        assert_eq!(migrated["merged"], 0);
+    }
+
+    #[test]
+    fn rejects_newer_versions() {
+        let raw = serde_json::json!({
+            "version": SCHEMA_VERSION + 1,
+            "entries": {},
+        });
+
+        match migrate(raw) {
+            Err(TwofaError::UnsupportedVersion(v)) => assert_eq!(v, SCHEMA_VERSION + 1),
+            _ => panic!("newer version accepted"),
+        }
+    }
+
+    #[test]
+    fn rejects_content_that_is_not_an_object() {
+        assert!(matches!(migrate(serde_json::json!([])), Err(TwofaError::CorruptVault(_))));
+    }
+}
@@ -0,0 +1,208 @@
+use crate::error::TwofaError;
+use crate::logger::Logger;
+use crate::storage::Storage;
+
+pub struct PermissionIssue {
+    pub path: String,
+    pub problem: String,
+    /// the storage or its directory, refused in strict mode
+    pub critical: bool,
+    /// files owned by another user cannot be fixed by us
+    pub fixable: bool,
+    is_dir: bool,
+}
+
+#[cfg(unix)]
+mod sys {
+    use std::fs::{read_dir, symlink_metadata, Permissions, set_permissions};
+    use std::os::unix::fs::{MetadataExt, PermissionsExt};
+    use crate::storage::{Storage, PRIVATE_DIR_MODE, PRIVATE_FILE_MODE};
+    use super::PermissionIssue;
+
+    fn check_path(path: &str, critical: bool, issues: &mut Vec<PermissionIssue>) -> bool {
+        let meta = match symlink_metadata(path) {
+            Ok(meta) => meta,
+            Err(_) => return false,
+        };
+        // links are not followed, fixing them would change their target
+        if meta.file_type().is_symlink() {
+            return false;
+        }
+
+        let is_dir = meta.is_dir();
+        let mode = meta.mode() & 0o777;
+        let expected = if is_dir { PRIVATE_DIR_MODE } else { PRIVATE_FILE_MODE };
+
+        if meta.uid() != unsafe { libc::geteuid() } {
+            issues.push(PermissionIssue {
+                path: path.to_owned(),
+                problem: format!("owned by user {}, not by you", meta.uid()),
+                critical,
+                fixable: false,
+                is_dir,
+            });
+        } else if mode & 0o077 != 0 {
+            issues.push(PermissionIssue {
+                path: path.to_owned(),
+                problem: format!("accessible by other users (mode {:04o}, expected {:04o})", mode, expected),
+                critical,
+                fixable: true,
+                is_dir,
+            });
+        }
+
+        is_dir
+    }
+
+    /// Checks the storage and home directories and everything below them.
+    pub fn check(storage_path: &Storage) -> Vec<PermissionIssue> {
+        let mut issues = Vec::new();
+        let mut dirs = Vec::new();
+
+        if check_path(&storage_path.dir[..], true, &mut issues) {
+            dirs.push(storage_path.dir.clone());
+        }
+        if storage_path.home != storage_path.dir && check_path(&storage_path.home[..], false, &mut issues) {
+            dirs.push(storage_path.home.clone());
+        }
+
+        while let Some(dir) = dirs.pop() {
+            let entries = match read_dir(&dir) {
+                Ok(entries) => entries,
+                Err(_) => continue,
+            };
+
+            for entry in entries.flatten() {
+                let path = entry.path().to_string_lossy().into_owned();
+                // a storage inside the home directory is already walked
+                if path == storage_path.dir {
+                    continue;
+                }
+                let critical = path == storage_path.en_file || path == storage_path.db_file;
+                if check_path(&path[..], critical, &mut issues) {
+                    dirs.push(path);
+                }
+            }
+        }
+
+        issues
+    }
+
+    pub fn fix(issue: &PermissionIssue) -> std::io::Result<()> {
+        let mode = if issue.is_dir { PRIVATE_DIR_MODE } else { PRIVATE_FILE_MODE };
+        set_permissions(&issue.path, Permissions::from_mode(mode))
+    }
+}
+
+#[cfg(not(unix))]
+mod sys {
+    use crate::storage::Storage;
+    use super::PermissionIssue;
+
+    pub fn check(_storage_path: &Storage) -> Vec<PermissionIssue> {
+        Vec::new()
+    }
+
+    pub fn fix(_issue: &PermissionIssue) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Returns files and directories of the storage that other users can
+/// access or that belong to someone else.
+pub fn check_permissions(storage_path: &Storage) -> Vec<PermissionIssue> {
+    sys::check(storage_path)
+}
+
+/// Refuses to touch a storage other users can read, or only warns about it
+/// if not `strict`. Other files are always only warned about.
+pub fn check_storage_permissions(storage_path: &Storage, strict: bool, logger: &Logger) -> Result<(), TwofaError> {
+    for issue in check_permissions(storage_path) {
+        if issue.critical && strict {
+            return Err(TwofaError::InsecurePermissions(issue.path));
+        }
+
+        logger.norm(
+            "insecure_permissions",
+            format!("Warning: '{}' is {}. Run 'twofa doctor --fix'", &issue.path, &issue.problem)
+                .as_str()
+        );
+    }
+
+    Ok(())
+}
+
+pub fn fix_permissions(issue: &PermissionIssue) -> Result<(), TwofaError> {
+    if !issue.fixable {
+        return Err(TwofaError::InsecurePermissions(issue.path.clone()));
+    }
+
+    match sys::fix(issue) {
+        Ok(()) => Ok(()),
+        Err(_) => Err(TwofaError::Io("Could not change permissions")),
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::fs::{set_permissions, Permissions};
+    use std::os::unix::fs::PermissionsExt;
+    use crate::test_util::{logger, storage_dir};
+
+    /// A private storage directory holding a storage and a config with the
+    /// given modes.
+    fn storage_with_modes(name: &str, storage: u32, config: u32) -> Storage {
+        let storage_path = storage_dir(name);
+        set_permissions(&storage_path.dir, Permissions::from_mode(0o700)).unwrap();
+        std::fs::write(&storage_path.db_file, "encrypted").unwrap();
+        set_permissions(&storage_path.db_file, Permissions::from_mode(storage)).unwrap();
+        let config_file = format!("{}/config.toml", &storage_path.dir);
+        std::fs::write(&config_file, "").unwrap();
+        set_permissions(&config_file, Permissions::from_mode(config)).unwrap();
+        storage_path
+    }
+
+    #[test]
+    fn refuses_a_readable_storage_only_when_strict() {
+        let storage_path = storage_with_modes("permissions-strict", 0o644, 0o600);
+
+        let err = check_storage_permissions(&storage_path, true, &logger()).err().unwrap();
+        assert_eq!(err.exit_code(), 12);
+        assert!(matches!(err, TwofaError::InsecurePermissions(path) if path == storage_path.db_file));
+        assert!(check_storage_permissions(&storage_path, false, &logger()).is_ok());
+
+        std::fs::remove_dir_all(&storage_path.dir).unwrap();
+    }
+
+    #[test]
+    fn only_warns_about_other_files() {
+        let storage_path = storage_with_modes("permissions-other", 0o600, 0o644);
+
+        let issues = check_permissions(&storage_path);
+        assert_eq!(issues.len(), 1);
+        assert!(!issues[0].critical);
+        assert!(check_storage_permissions(&storage_path, true, &logger()).is_ok());
+
+        std::fs::remove_dir_all(&storage_path.dir).unwrap();
+    }
+
+    #[test]
+    fn fixes_files_and_directories() {
+        let storage_path = storage_with_modes("permissions-fix", 0o644, 0o644);
+        set_permissions(&storage_path.dir, Permissions::from_mode(0o755)).unwrap();
+
+        let issues = check_permissions(&storage_path);
+        assert_eq!(issues.len(), 3);
+        for issue in issues.iter() {
+            fix_permissions(issue).unwrap();
+        }
+
+        assert!(check_permissions(&storage_path).is_empty());
+        let mode = |path: &str| std::fs::metadata(path).unwrap().permissions().mode() & 0o777;
+        assert_eq!(mode(&storage_path.dir), 0o700);
+        assert_eq!(mode(&storage_path.db_file), 0o600);
+
+        std::fs::remove_dir_all(&storage_path.dir).unwrap();
+    }
+}
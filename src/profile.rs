@@ -0,0 +1,116 @@
+use std::fs::read_dir;
+use std::path::Path;
+use crate::error::TwofaError;
+
+pub const PROFILE_VAR: &str = "TWOFA_PROFILE";
+/// the storage directly in the home directory
+pub const DEFAULT_PROFILE: &str = "default";
+const PROFILES_DIR: &str = "profiles";
+
+pub fn validate_profile_name(name: &str) -> Result<(), TwofaError> {
+    let valid = !name.is_empty()
+        && name.len() <= 64
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+
+    if valid {
+        Ok(())
+    } else {
+        Err(TwofaError::Other("Profile names may only contain letters, digits, '-' and '_'"))
+    }
+}
+
+pub fn profile_dir(home: &str, name: &str) -> String {
+    if name == DEFAULT_PROFILE {
+        home.to_owned()
+    } else {
+        format!("{}/{}/{}", home, PROFILES_DIR, name)
+    }
+}
+
+/// The directory of the new profile `name`, which must not exist yet.
+pub fn new_profile_dir(home: &str, name: &str) -> Result<String, TwofaError> {
+    validate_profile_name(name)?;
+
+    let dir = profile_dir(home, name);
+    if Path::new(&dir).exists() {
+        return Err(TwofaError::Other("Profile already exists"));
+    }
+    Ok(dir)
+}
+
+/// The directory of the existing profile `name` to delete. Refuses the
+/// default profile, which is the home directory itself.
+pub fn deletable_profile_dir(home: &str, name: &str) -> Result<String, TwofaError> {
+    validate_profile_name(name)?;
+    if name == DEFAULT_PROFILE {
+        return Err(TwofaError::Other("The default profile cannot be deleted"));
+    }
+
+    let dir = profile_dir(home, name);
+    if !Path::new(&dir).is_dir() {
+        return Err(TwofaError::ProfileNotFound(name.to_owned()));
+    }
+    Ok(dir)
+}
+
+/// Returns the default profile followed by all others sorted by name.
+pub fn list_profiles(home: &str) -> Result<Vec<String>, TwofaError> {
+    let mut profiles = vec![DEFAULT_PROFILE.to_owned()];
+
+    let entries = match read_dir(format!("{}/{}", home, PROFILES_DIR)) {
+        Ok(entries) => entries,
+        Err(_) => return Ok(profiles),
+    };
+
+    let mut named: Vec<String> = entries.flatten()
+        .filter(|entry| entry.path().is_dir())
+        .map(|entry| entry.file_name().to_string_lossy().into_owned())
+        .filter(|name| validate_profile_name(name).is_ok() && name != DEFAULT_PROFILE)
+        .collect();
+    named.sort();
+
+    profiles.append(&mut named);
+    Ok(profiles)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{create_dir_all, remove_dir_all};
+    use crate::test_util::temp_dir;
+
+    #[test]
+    fn lists_created_profiles_after_the_default() {
+        let home = temp_dir("profile-list");
+        assert_eq!(list_profiles(&home).unwrap(), vec!["default"]);
+
+        for name in ["work", "private"].iter() {
+            create_dir_all(new_profile_dir(&home, name).unwrap()).unwrap();
+        }
+        // not a profile name, so never listed or deleted
+        create_dir_all(format!("{}/{}/not.valid", home, PROFILES_DIR)).unwrap();
+
+        assert_eq!(list_profiles(&home).unwrap(), vec!["default", "private", "work"]);
+        assert!(new_profile_dir(&home, "work").is_err());
+        assert!(new_profile_dir(&home, "default").is_err());
+
+        remove_dir_all(deletable_profile_dir(&home, "work").unwrap()).unwrap();
+        assert_eq!(list_profiles(&home).unwrap(), vec!["default", "private"]);
+        assert!(matches!(deletable_profile_dir(&home, "work"), Err(TwofaError::ProfileNotFound(_))));
+
+        remove_dir_all(&home).unwrap();
+    }
+
+    #[test]
+    fn refuses_to_delete_the_default_or_paths() {
+        let home = temp_dir("profile-delete");
+        create_dir_all(format!("{}/{}", home, PROFILES_DIR)).unwrap();
+
+        for name in ["default", "..", ".", "", "../profiles", "work/..", "/tmp"].iter() {
+            assert!(deletable_profile_dir(&home, name).is_err(), "{}", name);
+        }
+        assert!(Path::new(&home).is_dir());
+
+        remove_dir_all(&home).unwrap();
+    }
+}
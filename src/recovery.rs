@@ -0,0 +1,270 @@
+use std::ffi::{CStr, CString};
+use std::fs::read_dir;
+use std::sync::atomic::{AtomicPtr, Ordering};
+use crate::error::TwofaError;
+use crate::logger::Logger;
+use crate::secret::SecretString;
+use crate::storage::{read_storage, Storage};
+use crate::vault::Vault;
+
+const BUFFER_PREFIX: &str = "buffer.storage";
+const BUFFER_SUFFIXES: &[&str] = &["", ".tmp", ".verify", ".verify.tmp"];
+
+/// Buffer files of this process, read by the signal handler. Set once and
+/// never freed, so the handler can use it without allocating.
+static BUFFER_PATHS: AtomicPtr<Vec<CString>> = AtomicPtr::new(std::ptr::null_mut());
+
+/// Overwrites a file with zeros before unlinking it. Only uses
+/// async-signal-safe calls, so it may run inside a signal handler.
+#[cfg(unix)]
+fn wipe_path(path: &CStr) -> bool {
+    let zeros = [0u8; 4096];
+
+    unsafe {
+        let fd = libc::open(path.as_ptr(), libc::O_WRONLY);
+        if fd < 0 {
+            return false;
+        }
+
+        let mut left = libc::lseek(fd, 0, libc::SEEK_END);
+        libc::lseek(fd, 0, libc::SEEK_SET);
+        while left > 0 {
+            let chunk = if left < zeros.len() as libc::off_t { left as usize } else { zeros.len() };
+            let written = libc::write(fd, zeros.as_ptr() as *const libc::c_void, chunk);
+            if written <= 0 {
+                break;
+            }
+            left -= written as libc::off_t;
+        }
+
+        libc::fsync(fd);
+        libc::close(fd);
+        libc::unlink(path.as_ptr()) == 0
+    }
+}
+
+#[cfg(not(unix))]
+fn wipe_path(path: &CStr) -> bool {
+    match path.to_str() {
+        Ok(p) => std::fs::remove_file(p).is_ok(),
+        Err(_) => false,
+    }
+}
+
+/// Securely removes a file holding plaintext secrets.
+pub fn wipe_file(path: &str) -> bool {
+    match CString::new(path) {
+        Ok(p) => wipe_path(&p),
+        Err(_) => false,
+    }
+}
+
+/// Wipes the buffer files of this process that still exist.
+pub fn wipe_buffers() {
+    let paths = BUFFER_PATHS.load(Ordering::SeqCst);
+    if paths.is_null() {
+        return;
+    }
+
+    for path in unsafe { &*paths }.iter() {
+        wipe_path(path);
+    }
+}
+
+#[cfg(unix)]
+extern "C" fn handle_signal(signal: libc::c_int) {
+    wipe_buffers();
+    unsafe { libc::_exit(128 + signal) };
+}
+
+/// Makes sure the plaintext buffers of this process are wiped when it is
+/// interrupted or panics.
+pub fn install_cleanup_handlers(storage_path: &Storage) {
+    let paths: Vec<CString> = BUFFER_SUFFIXES.iter()
+        .filter_map(|suffix| CString::new(format!("{}{}", &storage_path.de_file, suffix)).ok())
+        .collect();
+    BUFFER_PATHS.store(Box::into_raw(Box::new(paths)), Ordering::SeqCst);
+
+    #[cfg(unix)]
+    unsafe {
+        for signal in &[libc::SIGINT, libc::SIGTERM, libc::SIGHUP, libc::SIGQUIT] {
+            libc::signal(*signal, handle_signal as extern "C" fn(libc::c_int) as libc::sighandler_t);
+        }
+    }
+
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        wipe_buffers();
+        default_hook(info);
+    }));
+}
+
+#[cfg(unix)]
+fn is_running(pid: i32) -> bool {
+    if pid <= 0 || pid as u32 == std::process::id() {
+        return false;
+    }
+
+    let res = unsafe { libc::kill(pid, 0) };
+    res == 0 || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+#[cfg(not(unix))]
+fn is_running(_pid: i32) -> bool {
+    false
+}
+
+/// Returns buffer files left behind by twofa processes that are no longer
+/// running, newest first.
+pub fn find_leftover_buffers(storage_path: &Storage) -> Vec<String> {
+    let entries = match read_dir(&storage_path.dir) {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new(),
+    };
+
+    let mut leftovers: Vec<(std::time::SystemTime, String)> = Vec::new();
+    for entry in entries.flatten() {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if !name.starts_with(BUFFER_PREFIX) {
+            continue;
+        }
+
+        // `buffer.storage` of older versions has no pid
+        let pid = name[BUFFER_PREFIX.len()..]
+            .trim_start_matches('.')
+            .split('.')
+            .next()
+            .and_then(|pid| pid.parse::<i32>().ok());
+        if let Some(pid) = pid {
+            if is_running(pid) {
+                continue;
+            }
+        }
+
+        let modified = entry.metadata().ok()
+            .and_then(|m| m.modified().ok())
+            .unwrap_or(std::time::UNIX_EPOCH);
+        leftovers.push((modified, entry.path().to_string_lossy().into_owned()));
+    }
+
+    leftovers.sort_by_key(|(modified, _)| std::cmp::Reverse(*modified));
+    leftovers.into_iter().map(|(_, path)| path).collect()
+}
+
+/// The newest of `leftovers` holding a valid storage, which is the most
+/// recent content, if `ask` wants it back. Without a terminal nobody is
+/// asked and the buffers are only wiped.
+pub fn recoverable_buffer(leftovers: &[String], interactive: bool, ask: impl FnOnce() -> Result<bool, TwofaError>) -> Result<Option<Vault>, TwofaError> {
+    let recoverable = leftovers.iter().find_map(|path| {
+        let data = SecretString::new(read_storage(&path[..]).ok()?);
+        Vault::from_json(data.expose()).ok()
+    });
+
+    match recoverable {
+        Some(vault) if interactive && ask()? => Ok(Some(vault)),
+        _ => Ok(None),
+    }
+}
+
+/// Wipes every file of `leftovers`.
+pub fn wipe_leftovers(leftovers: &[String], logger: &Logger) -> Result<(), TwofaError> {
+    for path in leftovers.iter() {
+        if !wipe_file(&path[..]) {
+            return Err(TwofaError::Io("Could not wipe leftover buffer file"));
+        }
+        logger.min(
+            "buffer_wiped",
+            format!("Wiped leftover buffer '{}'", path)
+                .as_str()
+        );
+    }
+
+    Ok(())
+}
+
+pub fn is_interactive() -> bool {
+    #[cfg(unix)]
+    {
+        unsafe { libc::isatty(libc::STDIN_FILENO) == 1 }
+    }
+    #[cfg(not(unix))]
+    {
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::DefaultsConfig;
+    use crate::test_util::{logger, storage_dir};
+    use crate::vault::Entry;
+
+    /// plaintext left in a buffer, the content does not matter
+    const STORAGE: &str = r#"{"version":3,"entries":{"github":{"secret":"JBSWY3DPEHPK3PXP"}}}"#;
+
+    fn buffer(storage_path: &Storage, pid: i32) -> String {
+        format!("{}/{}.{}", &storage_path.dir, BUFFER_PREFIX, pid)
+    }
+
+    fn never_asked() -> Result<bool, TwofaError> {
+        panic!("nobody can be asked without a terminal");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn finds_buffers_of_processes_no_longer_running() {
+        let storage_path = storage_dir("recovery-find");
+        // no process has the largest pid, pid 1 always runs
+        let stale = buffer(&storage_path, i32::MAX);
+        let legacy = format!("{}/{}", &storage_path.dir, BUFFER_PREFIX);
+        std::fs::write(&stale, STORAGE).unwrap();
+        std::fs::write(&legacy, STORAGE).unwrap();
+        std::fs::write(buffer(&storage_path, 1), STORAGE).unwrap();
+        std::fs::write(format!("{}/twofa.storage", &storage_path.dir), "encrypted").unwrap();
+
+        let mut found = find_leftover_buffers(&storage_path);
+        found.sort();
+
+        assert_eq!(found, vec![legacy, stale]);
+        std::fs::remove_dir_all(&storage_path.dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn wipes_buffers_without_a_terminal() {
+        let storage_path = storage_dir("recovery-wipe");
+        let stale = buffer(&storage_path, i32::MAX);
+        std::fs::write(&stale, STORAGE).unwrap();
+        // a second name for the same data shows what is left on disk
+        let link = format!("{}/link", &storage_path.dir);
+        std::fs::hard_link(&stale, &link).unwrap();
+
+        let leftovers = find_leftover_buffers(&storage_path);
+        assert!(recoverable_buffer(&leftovers, false, never_asked).unwrap().is_none());
+        wipe_leftovers(&leftovers, &logger()).unwrap();
+
+        assert!(find_leftover_buffers(&storage_path).is_empty());
+        let left = std::fs::read(&link).unwrap();
+        assert_eq!(left.len(), STORAGE.len());
+        assert!(left.iter().all(|byte| *byte == 0));
+        std::fs::remove_dir_all(&storage_path.dir).unwrap();
+    }
+
+    #[test]
+    fn recovers_the_buffer_when_asked() {
+        let storage_path = storage_dir("recovery-ask");
+        let damaged = buffer(&storage_path, 1);
+        let newest_valid = buffer(&storage_path, 2);
+        std::fs::write(&damaged, "{\"version\":3,\"entr").unwrap();
+        let mut vault = Vault::new();
+        vault.insert_entry("github", Entry::new(SecretString::from("JBSWY3DPEHPK3PXP"), &DefaultsConfig::default()), 1000);
+        std::fs::write(&newest_valid, vault.to_json().unwrap().expose()).unwrap();
+
+        let leftovers = vec![damaged, newest_valid];
+        let recovered = recoverable_buffer(&leftovers, true, || Ok(true)).unwrap().unwrap();
+        assert_eq!(recovered.entry("github").unwrap().secret.expose(), "JBSWY3DPEHPK3PXP");
+        assert!(recoverable_buffer(&leftovers, true, || Ok(false)).unwrap().is_none());
+        std::fs::remove_dir_all(&storage_path.dir).unwrap();
+    }
+}
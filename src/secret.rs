@@ -0,0 +1,130 @@
+use std::fmt;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use zeroize::Zeroize;
+use encryptfile::PwKeyArray;
+
+/// String holding secret material. The buffer is locked into memory,
+/// wiped on drop and never printed by `Debug` or `Display`.
+///
+/// Pages are never unlocked: `munlock` works on whole pages, which may
+/// still hold other secrets. They are released when the process exits.
+pub struct SecretString(String);
+
+impl SecretString {
+    pub fn new(value: String) -> Self {
+        lock_memory(value.as_ptr(), value.capacity());
+        Self(value)
+    }
+
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl From<&str> for SecretString {
+    fn from(value: &str) -> Self {
+        Self::new(value.to_owned())
+    }
+}
+
+impl From<String> for SecretString {
+    fn from(value: String) -> Self {
+        Self::new(value)
+    }
+}
+
+impl PartialEq for SecretString {
+    fn eq(&self, other: &Self) -> bool {
+        self.0 == other.0
+    }
+}
+
+impl Drop for SecretString {
+    fn drop(&mut self) {
+        self.0.zeroize();
+    }
+}
+
+impl Serialize for SecretString {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.0)
+    }
+}
+
+impl<'de> Deserialize<'de> for SecretString {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer).map(SecretString::new)
+    }
+}
+
+impl fmt::Debug for SecretString {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "SecretString(***)")
+    }
+}
+
+impl fmt::Display for SecretString {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "***")
+    }
+}
+
+/// Key the vault is encrypted with, kept on the heap so it is never moved
+/// after being locked into memory.
+pub struct VaultKey(Box<PwKeyArray>);
+
+impl VaultKey {
+    pub fn new(mut key: PwKeyArray) -> Self {
+        let boxed = Box::new(key);
+        key.zeroize();
+        lock_memory(boxed.as_ptr(), boxed.len());
+        Self(boxed)
+    }
+
+    pub fn expose(&self) -> &PwKeyArray {
+        &self.0
+    }
+}
+
+impl Drop for VaultKey {
+    fn drop(&mut self) {
+        self.0.zeroize();
+    }
+}
+
+impl fmt::Debug for VaultKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "VaultKey(***)")
+    }
+}
+
+#[cfg(unix)]
+fn lock_memory(ptr: *const u8, len: usize) {
+    if len > 0 {
+        // best effort, RLIMIT_MEMLOCK may be too small
+        unsafe { libc::mlock(ptr as *const libc::c_void, len) };
+    }
+}
+
+#[cfg(not(unix))]
+fn lock_memory(_ptr: *const u8, _len: usize) {}
+
+/// Keeps secrets out of core dumps and away from same-user debuggers
+/// for the lifetime of the process. Files and directories are created
+/// accessible by the owner only, including the temp files of encryptfile.
+pub fn harden_process() {
+    #[cfg(target_os = "linux")]
+    unsafe {
+        libc::prctl(libc::PR_SET_DUMPABLE, 0, 0, 0, 0);
+    }
+
+    #[cfg(unix)]
+    unsafe {
+        let limit = libc::rlimit {
+            rlim_cur: 0,
+            rlim_max: 0,
+        };
+        libc::setrlimit(libc::RLIMIT_CORE, &limit);
+        libc::umask(0o077);
+    }
+}
@@ -0,0 +1,117 @@
+use serde::{Deserialize, Serialize};
+use zeroize::Zeroizing;
+use crate::clock::Clock;
+use crate::crypto::{random_bytes, seal, open, key_from_bytes, SEAL_KEY_SIZE};
+use crate::storage::{read_storage, save_storage, check_storage, delete_file};
+use crate::logger::Logger;
+use crate::secret::{SecretString, VaultKey};
+
+pub const SESSION_VAR: &str = "TWOFA_SESSION";
+
+#[derive(Serialize, Deserialize)]
+struct SessionFile {
+    key: String,
+    expires: u64,
+}
+
+fn write_session_file(path: &str, session: &SessionFile) -> Result<(), &'static str> {
+    let data = match serde_json::to_string(session) {
+        Ok(d) => d,
+        Err(_) => return Err("Could not serialize session"),
+    };
+
+    match save_storage(path, &data[..]) {
+        Ok(_) => Ok(()),
+        Err(_) => Err("Could not write session file"),
+    }
+}
+
+/// Wraps the vault key with a fresh session key kept in `path` and returns the token.
+/// Starting a new session invalidates all earlier tokens.
+pub fn create_session(path: &str, scope: &str, key: &VaultKey, timeout: u64, clock: &dyn Clock, logger: &Logger) -> Result<String, &'static str> {
+    let session_key = Zeroizing::new(random_bytes(SEAL_KEY_SIZE)?);
+    let token = seal(&session_key[..], scope.as_bytes(), &key.expose()[..])?;
+
+    write_session_file(path, &SessionFile {
+        key: hex::encode(&session_key[..]),
+        expires: clock.now() + timeout,
+    })?;
+
+    logger.min(
+        "session_created",
+        format!("Session created, expires in {} seconds", &timeout)
+            .as_str()
+    );
+
+    Ok(hex::encode(&token))
+}
+
+pub fn open_session(path: &str, scope: &str, token: &str, clock: &dyn Clock, logger: &Logger) -> Result<VaultKey, &'static str> {
+    if !check_storage(path) {
+        return Err("No active session");
+    }
+
+    let data = match read_storage(path) {
+        Ok(d) => SecretString::new(d),
+        Err(_) => return Err("Could not read session file"),
+    };
+
+    let session: SessionFile = match serde_json::from_str(data.expose()) {
+        Ok(s) => s,
+        Err(_) => return Err("Could not parse session file"),
+    };
+
+    if session.expires <= clock.now() {
+        logger.min("session_expired", "Session expired");
+        end_session(path, logger);
+        return Err("Session expired");
+    }
+
+    let session_key = match hex::decode(&session.key) {
+        Ok(k) => Zeroizing::new(k),
+        Err(_) => return Err("Invalid session key"),
+    };
+    let sealed = match hex::decode(token.trim()) {
+        Ok(t) => t,
+        Err(_) => return Err("Invalid session token"),
+    };
+
+    let key = open(&session_key[..], scope.as_bytes(), &sealed[..])?;
+    match key_from_bytes(&key[..]) {
+        Some(k) => Ok(k),
+        None => Err("Invalid session token"),
+    }
+}
+
+pub fn end_session(path: &str, logger: &Logger) -> bool {
+    if !check_storage(path) {
+        return false;
+    }
+
+    delete_file(path, logger).is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::FixedClock;
+    use crate::test_util::{key, logger, temp_dir};
+
+    #[test]
+    fn sessions_expire_after_the_timeout() {
+        let dir = temp_dir("session-expiry");
+        let path = format!("{}/session.json", &dir);
+        let logger = logger();
+        let key = key(3);
+
+        let token = create_session(&path[..], "scope", &key, 60, &FixedClock(1000), &logger).unwrap();
+
+        let opened = open_session(&path[..], "scope", &token[..], &FixedClock(1059), &logger).unwrap();
+        assert_eq!(opened.expose()[..], key.expose()[..]);
+        assert_eq!(open_session(&path[..], "scope", &token[..], &FixedClock(1060), &logger).err(), Some("Session expired"));
+        // an expired session is ended
+        assert!(!check_storage(&path[..]));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
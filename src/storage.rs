@@ -1,5 +1,5 @@
 #![allow(dead_code,unused_variables)]
-use std::fs::{ File, remove_file };
+use std::fs::{ File, OpenOptions, DirBuilder, copy, remove_file, rename };
 use std::path::Path;
 use std::io::{Read, Write};
 use std::result::{ Result };
@@ -16,30 +16,46 @@ pub enum FileSaveError {
     AlreadyExists,
 }
 
-pub enum FileDeleteError {
-    FileExists,
-    FileNotFound,
-    FileDeleteError,
-}
-
 pub struct Storage {
+    pub home: String,
     pub dir: String,
+    /// `None` if the storage was given by its directory
+    pub profile: Option<String>,
     pub en_file: String,
+    pub db_file: String,
     pub de_file: String,
+    pub key_check: String,
+    pub lock: String,
+    pub backup_dir: String,
+    pub config: String,
+    pub profile_config: Option<String>,
+    pub session: String,
+    pub log_dir: String,
 }
 
 impl Storage {
-    pub fn new(dir: String, en_file: String, de_file: String) -> Self {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(home: String, dir: String, profile: Option<String>, en_file: String, db_file: String, de_file: String, key_check: String, lock: String, backup_dir: String, config: String, profile_config: Option<String>, session: String, log_dir: String) -> Self {
         Self {
+            home,
             dir,
+            profile,
             en_file,
+            db_file,
             de_file,
+            key_check,
+            lock,
+            backup_dir,
+            config,
+            profile_config,
+            session,
+            log_dir,
         }
     }
 }
 
 pub fn read_storage(path: &str) -> Result<String, FileReadError> {
-    let f = File::open(&path);
+    let f = File::open(path);
     match f {
         Ok(mut file) => {
             let mut buffer = String::new();
@@ -48,8 +64,7 @@ pub fn read_storage(path: &str) -> Result<String, FileReadError> {
                 Ok(_) => {
                     Ok(buffer)
                 },
-                Err(e) => {
-                    println!("{}", e);
+                Err(_) => {
                     Err(FileReadError::NoContent)
                 }
             }
@@ -60,31 +75,112 @@ pub fn read_storage(path: &str) -> Result<String, FileReadError> {
     }
 }
 
-pub fn save_storage(path: &str, data: String) -> Result<(), FileSaveError> {
-    let f = File::create(&path);
-    match f {
+pub fn temp_path(path: &str) -> String {
+    format!("{}.tmp", path)
+}
+
+pub const PRIVATE_DIR_MODE: u32 = 0o700;
+pub const PRIVATE_FILE_MODE: u32 = 0o600;
+
+/// Creates `path` and its missing parents accessible by the owner only.
+pub fn create_private_dir(path: &str) -> std::io::Result<()> {
+    let mut builder = DirBuilder::new();
+    builder.recursive(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::DirBuilderExt;
+        builder.mode(PRIVATE_DIR_MODE);
+    }
+
+    builder.create(path)
+}
+
+/// Restricts an existing file to the owner. A no-op where file modes
+/// do not exist.
+pub fn set_private(path: &str) -> std::io::Result<()> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(PRIVATE_FILE_MODE))?;
+    }
+
+    Ok(())
+}
+
+/// Writes `data` to a temp file next to `path` and renames it over `path`,
+/// so a crash leaves either the old or the new content behind. The file is
+/// only readable by the owner.
+pub fn save_storage(path: &str, data: &str) -> Result<(), FileSaveError> {
+    let tmp = temp_path(path);
+    // a leftover temp file may have other permissions
+    let _ = remove_file(&tmp);
+
+    let mut options = OpenOptions::new();
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(PRIVATE_FILE_MODE);
+    }
+
+    match options.write(true).create_new(true).open(&tmp) {
         Ok(mut file) => {
-            match file.write_all(data.as_bytes()) {
-                Ok(_) => {
-                    Ok(())
-                },
-                Err(e) => {
-                    Err(FileSaveError::NoSave)
-                }
+            if let Err(e) = file.write_all(data.as_bytes()).and_then(|_| file.sync_all()) {
+                let _ = remove_file(&tmp);
+                return Err(FileSaveError::NoSave);
             }
         },
         Err(e) => {
-            Err(FileSaveError::NoCreate)
+            return Err(FileSaveError::NoCreate);
         }
     }
+
+    replace_storage(&tmp, path)
+}
+
+/// Flushes `tmp` to disk and renames it over `path`. The file ends up
+/// readable by the owner only, whoever created it.
+pub fn replace_storage(tmp: &str, path: &str) -> Result<(), FileSaveError> {
+    if let Err(e) = set_private(tmp).and_then(|_| File::open(tmp)).and_then(|file| file.sync_all()) {
+        let _ = remove_file(tmp);
+        return Err(FileSaveError::NoSave);
+    }
+
+    if let Err(e) = rename(tmp, path) {
+        let _ = remove_file(tmp);
+        return Err(FileSaveError::NoSave);
+    }
+
+    sync_parent_dir(path);
+    Ok(())
+}
+
+/// Makes the rename itself durable. Best effort, not every platform
+/// can open directories.
+fn sync_parent_dir(path: &str) {
+    let dir = match Path::new(path).parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+
+    if let Ok(dir) = File::open(dir) {
+        let _ = dir.sync_all();
+    }
+}
+
+pub fn copy_storage(from: &str, to: &str) -> Result<(), FileSaveError> {
+    match copy(from, to).and_then(|_| set_private(to)) {
+        Ok(_) => Ok(()),
+        Err(e) => Err(FileSaveError::NoSave),
+    }
 }
 
 pub fn delete_file(path: &str, logger: &Logger) -> Result<(), &'static str> {
-    if let Err(e) = remove_file(&path) {
+    if let Err(e) = remove_file(path) {
         return Err("Error deleting file");
     }
 
     logger.min(
+        "file_deleted",
         format!("File '{}' deleted", &path)
             .as_str()
     );
@@ -96,19 +192,111 @@ pub fn check_storage(path: &str) -> bool {
     Path::new(&path).exists()
 }
 
-pub fn get_storage_path() -> Storage {
-    let mut folder_path = std::env::var("HOME").expect("HOME var needed");
-    folder_path.push_str("/.twofa");
+pub fn config_file(dir: &str) -> String {
+    format!("{}/config.toml", dir)
+}
 
+/// Files of the storage in `folder_path`, config and logs in `home`.
+pub fn get_storage_path(home: String, folder_path: String, profile: Option<String>) -> Storage {
+    // readers share the lock, so every process decrypts to its own buffer
     let mut de_file = folder_path.clone();
-    de_file.push_str("/buffer.storage");
+    de_file.push_str(format!("/buffer.storage.{}", std::process::id()).as_str());
 
     let mut en_file = folder_path.clone();
     en_file.push_str("/twofa.storage");
 
+    let mut db_file = folder_path.clone();
+    db_file.push_str("/twofa.db");
+
+    let mut key_check = folder_path.clone();
+    key_check.push_str("/twofa.check");
+
+    let mut lock = folder_path.clone();
+    lock.push_str("/twofa.lock");
+
+    let mut backup_dir = folder_path.clone();
+    backup_dir.push_str("/backups");
+
+    let mut session = folder_path.clone();
+    session.push_str("/session.json");
+
+    let config = config_file(&home[..]);
+
+    // a profile in its own directory may override the global config
+    let profile_config = if folder_path != home && profile.is_some() {
+        Some(config_file(&folder_path[..]))
+    } else {
+        None
+    };
+
+    let mut log_dir = home.clone();
+    log_dir.push_str("/logs");
+
     Storage::new(
-        folder_path,
+        home,
+            folder_path,
+            profile,
             en_file,
+            db_file,
             de_file,
+            key_check,
+            lock,
+            backup_dir,
+            config,
+            profile_config,
+            session,
+            log_dir,
     )
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::temp_dir;
+
+    #[test]
+    fn saves_through_a_private_temp_file() {
+        let dir = temp_dir("storage-save");
+        let path = format!("{}/twofa.storage", &dir);
+        std::fs::write(&path, "old").unwrap();
+        // left by an interrupted save, maybe with other permissions
+        std::fs::write(temp_path(&path), "leftover").unwrap();
+
+        assert!(save_storage(&path, "new").is_ok());
+
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "new");
+        assert!(!check_storage(&temp_path(&path)));
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(std::fs::metadata(&path).unwrap().permissions().mode() & 0o777, PRIVATE_FILE_MODE);
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn keeps_the_previous_file_when_saving_fails() {
+        let dir = temp_dir("storage-save-failed");
+        let path = format!("{}/twofa.storage", &dir);
+        std::fs::write(&path, "old").unwrap();
+        // the temp file can not be created where a directory is
+        std::fs::create_dir(temp_path(&path)).unwrap();
+
+        assert!(save_storage(&path, "new").is_err());
+
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "old");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn removes_the_temp_file_when_the_rename_fails() {
+        let dir = temp_dir("storage-replace-failed");
+        let tmp = format!("{}/twofa.storage.tmp", &dir);
+        std::fs::write(&tmp, "new").unwrap();
+
+        assert!(replace_storage(&tmp, &format!("{}/missing/twofa.storage", &dir)).is_err());
+
+        assert!(!check_storage(&tmp));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
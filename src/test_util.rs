@@ -0,0 +1,30 @@
+use crate::crypto::{key_from_bytes, KEY_SIZE};
+use crate::logger::Logger;
+use crate::secret::VaultKey;
+use crate::storage::{get_storage_path, Storage};
+
+// Fixtures shared by the tests of several modules.
+
+pub fn logger() -> Logger {
+    Logger::new(0, false)
+}
+
+/// A key made of `byte`, without the cost of deriving one from a password.
+pub fn key(byte: u8) -> VaultKey {
+    key_from_bytes(&[byte; KEY_SIZE]).unwrap()
+}
+
+/// An empty directory for the test `name` of this process, e.g.
+/// `backup-prune`. Tests remove it when they are done.
+pub fn temp_dir(name: &str) -> String {
+    let dir = std::env::temp_dir().join(format!("twofa-test-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir.to_string_lossy().into_owned()
+}
+
+/// The paths of a storage kept in `temp_dir(name)`, none of them created.
+pub fn storage_dir(name: &str) -> Storage {
+    let dir = temp_dir(name);
+    get_storage_path(dir.clone(), dir, None)
+}
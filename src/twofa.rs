@@ -1,334 +1,124 @@
-use boringauth::oath::{ TOTPBuilder, HashFunction };
-use serde_json::{Map, Value};
-use std::convert::TryInto;
-use std::fmt::Display;
-use super::Opts;
-
+use boringauth::oath::{ TOTPBuilder, HashFunction, ErrorCode };
+use serde::{Deserialize, Serialize};
+use crate::cli::AddOpts;
+use crate::clock::Clock;
+use crate::config::DefaultsConfig;
+use crate::secret::SecretString;
+use crate::error::TwofaError;
+use crate::vault::Entry;
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
 pub enum Encoding {
     Base32,
     Hex,
     Ascii,
 }
 
-pub struct TwofaSettings {
-    pub secret: Option<String>,
-    pub window: Option<u32>,
-    pub hash: Option<HashFunction>,
-    pub encoding: Option<Encoding>
-}
-
-impl TwofaSettings {
-    pub fn new() -> Self {
-        Self {
-            secret: None,
-            window: None,
-            hash: None,
-            encoding: None,
+impl Encoding {
+    pub fn from_name(name: &str) -> Result<Self, TwofaError> {
+        match name {
+            "base32" => Ok(Encoding::Base32),
+            "hex" => Ok(Encoding::Hex),
+            "ascii" => Ok(Encoding::Ascii),
+            _ => Err(TwofaError::InvalidSecret("encoding not supported")),
         }
     }
 
-    pub fn set_secret(&mut self, secret: String) -> &mut Self {
-        self.secret = Some(secret);
-        self
-    }
-
-    pub fn set_window(&mut self, window: Option<u32>) -> &mut Self {
-        match window {
-            Some(w) => {
-                self.window = Some(w);
-                self
-            },
-            None => {
-                self.window = Some(30);
-                self
-            }
+    pub fn name(&self) -> &'static str {
+        match self {
+            Encoding::Base32 => "base32",
+            Encoding::Hex => "hex",
+            Encoding::Ascii => "ascii",
         }
     }
+}
 
-    pub fn set_hash(&mut self, hash: Option<HashFunction>) -> &mut Self {
-        match hash {
-            Some(hf) => {
-                self.hash = Some(hf);
-                self
-            },
-            None => {
-                self.hash = Some(HashFunction::Sha512);
-                self
-            }
-        }
-    }
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Hash {
+    Sha1,
+    Sha256,
+    Sha512,
+}
 
-    pub fn set_encoding(&mut self, encoding: Option<Encoding>) -> &mut Self {
-        match encoding {
-            Some(en) => {
-                self.encoding = Some(en);
-                self
-            },
-            None => {
-                self.encoding = Some(Encoding::Base32);
-                self
-            }
+impl Hash {
+    pub fn from_name(name: &str) -> Result<Self, TwofaError> {
+        match name {
+            "sha1" => Ok(Hash::Sha1),
+            "sha256" => Ok(Hash::Sha256),
+            "sha512" => Ok(Hash::Sha512),
+            _ => Err(TwofaError::InvalidSecret("hash not supported")),
         }
     }
 
-    pub fn to_json(&self) -> Value {
-        let mut encoding = String::from("");
-        let mut hash = String::from("");
-
-        match &self.encoding {
-            Some(e) => {
-                match e {
-                    Encoding::Hex => {
-                        encoding.push_str("hex");
-                    },
-                    Encoding::Ascii => {
-                        encoding.push_str("ascii");
-                    },
-                    Encoding::Base32 => {
-                        encoding.push_str("base32");
-                    },
-                }
-            },
-            None => {
-                encoding.push_str("base32");
-            }
-        };
-
-        match &self.hash {
-            Some(h) => {
-                match h {
-                    HashFunction::Sha256 => {
-                        hash.push_str("sha256");
-                    },
-                    HashFunction::Sha512 => {
-                        hash.push_str("sha512");
-                    },
-                    #[allow(deprecated)]
-                    HashFunction::Sha1 => {
-                        hash.push_str("sha1");
-                    },
-                };
-            },
-            None => {
-                hash.push_str("sha512")
-            }
-        };
-
-        serde_json::json!({
-            "encoding": encoding,
-            "window": self.window.clone().unwrap(),
-            "hash": hash,
-            "secret": self.secret.clone().unwrap(),
-        })
+    pub fn name(&self) -> &'static str {
+        match self {
+            Hash::Sha1 => "sha1",
+            Hash::Sha256 => "sha256",
+            Hash::Sha512 => "sha512",
+        }
     }
-}
 
-impl Display for TwofaSettings {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let mut hash_string = String::from("");
-        let mut encoding = String::from("");
-        match self.hash.unwrap() {
-            HashFunction::Sha512 => {
-                hash_string.push_str("SHA512");
-            },
-            HashFunction::Sha256 => {
-                hash_string.push_str("SHA256");
-            },
+    fn function(&self) -> HashFunction {
+        match self {
             #[allow(deprecated)]
-            HashFunction::Sha1 => {
-                hash_string.push_str("SHA1");
-            }
-        };
-
-        match &self.encoding {
-            Some(e) => {
-                match e {
-                    Encoding::Base32 => {
-                        encoding.push_str("base32");
-                    },
-                    Encoding::Hex => {
-                        encoding.push_str("hex");
-                    },
-                    Encoding::Ascii => {
-                        encoding.push_str("ascii");
-                    }
-                }
-            },
-            None => {
-                encoding.push_str("undefined");
-            }
+            Hash::Sha1 => HashFunction::Sha1,
+            Hash::Sha256 => HashFunction::Sha256,
+            Hash::Sha512 => HashFunction::Sha512,
         }
-
-        write!(f, "s: {}; h: {}; w: {}; e: {}",
-                 self.secret.as_ref().unwrap(),
-                    hash_string,
-                self.window.as_ref().unwrap(),
-                encoding,
-                )
     }
 }
 
-pub fn create_twofa_settings_with_input(data: &Opts) -> Result<TwofaSettings, &'static str> {
-    let mut settings: TwofaSettings = TwofaSettings::new();
+/// Settings not given on the command line are taken from `defaults`.
+pub fn create_entry_with_input(data: &AddOpts, secret: SecretString, defaults: &DefaultsConfig) -> Result<Entry, TwofaError> {
+    let mut entry = Entry::new(secret, defaults);
 
-    if let None = &data.secret {
-        println!("Secret is needed. -s --secret");
-        std::process::exit(0);
+    if let Some(window) = data.window {
+        entry.window = window;
     }
-
-    settings.set_secret(data.secret.clone().unwrap());
-
-    let parsed_integer: u32 = data.window.as_u64().unwrap().try_into().unwrap();
-    settings.set_window(Some(parsed_integer));
-
-    match &data.hash[..] {
-        "sha256" => {
-            settings.set_hash(Some(HashFunction::Sha256));
-        },
-        "sha512" => {
-            settings.set_hash(Some(HashFunction::Sha512));
-        },
-        #[allow(deprecated)]
-        "sha1" => {
-            settings.set_hash(Some(HashFunction::Sha1));
-        },
-        _ => {
-            println!("Hash not supported");
-            std::process::exit(0);
-        }
+    if let Some(hash) = &data.hash {
+        entry.hash = Hash::from_name(&hash[..])?;
     }
-
-    match &data.encoding[..] {
-        "base32" => {
-            settings.set_encoding(Some(Encoding::Base32));
-        },
-        "ascii" => {
-            settings.set_encoding(Some(Encoding::Ascii));
-        },
-        "hex" => {
-            settings.set_encoding(Some(Encoding::Hex));
-        },
-        _ => {
-            println!("Unsupported encoding");
-            std::process::exit(1);
-        }
+    if let Some(encoding) = &data.encoding {
+        entry.encoding = Encoding::from_name(&encoding[..])?;
     }
-
-    Ok(settings)
-}
-
-pub fn create_twofa_settings(data: Option<Map<String, Value>>) -> Result<TwofaSettings, &'static str>{
-    let mut settings: TwofaSettings = TwofaSettings::new();
-
-    match data {
-        Some(map) => {
-            for item in map.iter() {
-                match &item.0[..] {
-                    "secret" => {
-                        if let Value::String(secret) = item.1.clone() {
-                            settings.set_secret(secret.clone());
-                        } else {
-                            return Err("Mismatched secret stored");
-                        }
-                    },
-                    "window" => {
-                        if let Value::Number(window) = item.1.clone() {
-                            let parsed_integer = window.as_u64().expect("Could not parse integer 'window'");
-                            let parsed_to_u32: u32 = parsed_integer.try_into().expect("Could not parse 'window' to u32");
-                            settings.set_window(Some(parsed_to_u32));
-                        } else {
-                            return Err("Mismatched window type stored");
-                        }
-                    },
-                    "hash" => {
-                        if let Value::String(hash) = item.1.clone() {
-                            match hash.as_str() {
-                                "sha512" => {
-                                    settings.set_hash(Some(HashFunction::Sha512));
-                                },
-                                "sha256" => {
-                                    settings.set_hash(Some(HashFunction::Sha256));
-                                },
-                                #[allow(deprecated)]
-                                "sha1" => {
-                                    settings.set_hash(Some(HashFunction::Sha1));
-                                },
-                                _ => {
-                                    return Err("Mismatched hash stored");
-                                }
-                            };
-                        }
-                    },
-                    "encoding" => {
-                        if let Value::String(encoding) = item.1.clone() {
-                            match encoding.as_str() {
-                                "base32" => {
-                                    settings.set_encoding(Some(Encoding::Base32));
-                                },
-                                "hex" => {
-                                    settings.set_encoding(Some(Encoding::Hex));
-                                },
-                                "ascii" => {
-                                    settings.set_encoding(Some(Encoding::Ascii));
-                                },
-                                _ => {
-                                    return Err("Unknown encoding saved");
-                                }
-                            }
-                        }
-                    },
-                    _ => {
-                        println!("Value not needed: {}", &item.0[..]);
-                    }
-                }
-            };
-            Ok(settings)
-        },
-        None => {
-            Err("No data for application saved")
-        }
+    if let Some(digits) = data.digits {
+        entry.digits = digits;
     }
+    entry.issuer = data.issuer.clone();
+
+    Ok(entry)
 }
 
-pub fn create_code_with_twofa_settings(ts: &TwofaSettings) -> Result<String, &'static str> {
+/// Code of `entry` at the time of `clock`.
+pub fn create_code(entry: &Entry, clock: &dyn Clock) -> Result<String, TwofaError> {
     let mut builder = TOTPBuilder::new();
 
-    match &ts.secret {
-        Some(secret) => {
-            if let Some(encoding) = &ts.encoding {
-                match encoding {
-                    Encoding::Ascii => {
-                        builder.ascii_key(secret.as_str());
-                    },
-                    Encoding::Hex => {
-                        builder.hex_key(secret.as_str());
-                    },
-                    Encoding::Base32 => {
-                        builder.base32_key(secret.as_str());
-                    }
-                };
-            } else {
-                return Err("Encoding is missing");
-            }
+    match entry.encoding {
+        Encoding::Ascii => {
+            builder.ascii_key(entry.secret.expose());
         },
-        None => {
-            return Err("Secret is missing");
+        Encoding::Hex => {
+            builder.hex_key(entry.secret.expose());
+        },
+        Encoding::Base32 => {
+            builder.base32_key(entry.secret.expose());
         }
     };
 
-    if let Some(window) = ts.window {
-        builder.period(window);
-    } else {
-        builder.period(30);
-    }
+    builder.period(entry.window);
+    builder.output_len(entry.digits as usize);
+    builder.hash_function(entry.hash.function());
+    builder.timestamp(clock.now() as i64);
 
-    if let Some(hash) = ts.hash {
-        builder.hash_function(hash);
-    } else {
-        builder.hash_function(HashFunction::Sha512);
+    match builder.finalize() {
+        Ok(totp) => Ok(totp.generate()),
+        Err(ErrorCode::InvalidKey) | Err(ErrorCode::InvalidKeyLen) | Err(ErrorCode::InvalidBaseLen) | Err(ErrorCode::KeyNullPtr) => {
+            Err(TwofaError::InvalidSecret("secret does not match its encoding"))
+        },
+        Err(ErrorCode::CodeTooSmall) | Err(ErrorCode::CodeTooBig) => Err(TwofaError::Other("Number of digits not supported")),
+        Err(ErrorCode::InvalidPeriod) => Err(TwofaError::Other("Window not supported")),
+        Err(_) => Err(TwofaError::Other("Could not generate code")),
     }
-
-    let code = builder.finalize().expect("Could not generate code").generate();
-
-    Ok(code)
 }
@@ -0,0 +1,193 @@
+use std::collections::BTreeMap;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use crate::config::{DefaultsConfig, DIGITS};
+use crate::error::TwofaError;
+use crate::helper::wipe_json;
+use crate::migrate::{migrate, SCHEMA_VERSION};
+use crate::secret::SecretString;
+use crate::twofa::{Encoding, Hash};
+
+/// Decrypted content of the storage.
+///
+/// Fields this version does not know are kept in `extra` and written back
+/// unchanged, so saving with an older binary keeps data of newer ones.
+#[derive(Serialize, Deserialize)]
+pub struct Vault {
+    pub version: u32,
+    #[serde(default)]
+    pub entries: BTreeMap<String, Entry>,
+    /// applications removed, with the time they were removed, so a merge
+    /// with a copy that still has them does not bring them back
+    pub deleted: BTreeMap<String, u64>,
+    /// seconds since epoch of the last merge, 0 if never merged
+    pub merged: u64,
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+    /// version the content was upgraded from while loading
+    #[serde(skip)]
+    pub migrated_from: Option<u32>,
+}
+
+/// Settings missing in older storages are filled in by the migrations,
+/// so none of them has a default here.
+#[derive(Serialize, Deserialize)]
+pub struct Entry {
+    pub secret: SecretString,
+    pub window: u32,
+    pub hash: Hash,
+    pub encoding: Encoding,
+    pub digits: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub issuer: Option<String>,
+    /// seconds since epoch of the last change, 0 if unknown
+    pub modified: u64,
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+impl Vault {
+    pub fn new() -> Self {
+        Self {
+            version: SCHEMA_VERSION,
+            entries: BTreeMap::new(),
+            deleted: BTreeMap::new(),
+            merged: 0,
+            extra: Map::new(),
+            migrated_from: None,
+        }
+    }
+
+    /// Parses storage content, upgrades it to the current schema and
+    /// validates it.
+    pub fn from_json(data: &str) -> Result<Self, TwofaError> {
+        let raw: Value = match serde_json::from_str(data) {
+            Ok(v) => v,
+            Err(_) => return Err(TwofaError::CorruptVault("could not parse storage")),
+        };
+
+        let (raw, from) = migrate(raw)?;
+
+        let mut vault: Vault = match serde_json::from_value(raw) {
+            Ok(v) => v,
+            Err(_) => return Err(TwofaError::CorruptVault("storage does not match the schema")),
+        };
+
+        vault.validate()?;
+        if from != SCHEMA_VERSION {
+            vault.migrated_from = Some(from);
+        }
+        Ok(vault)
+    }
+
+    pub fn to_json(&self) -> Result<SecretString, TwofaError> {
+        match serde_json::to_string(self) {
+            Ok(s) => Ok(SecretString::new(s)),
+            Err(_) => Err(TwofaError::Other("Could not serialize storage")),
+        }
+    }
+
+    pub fn to_json_pretty(&self) -> Result<SecretString, TwofaError> {
+        match serde_json::to_string_pretty(self) {
+            Ok(s) => Ok(SecretString::new(s)),
+            Err(_) => Err(TwofaError::Other("Could not serialize storage")),
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), TwofaError> {
+        for (app, entry) in self.entries.iter() {
+            if app.is_empty() {
+                return Err(TwofaError::CorruptVault("application without a name"));
+            }
+            entry.validate()?;
+        }
+
+        Ok(())
+    }
+
+    pub fn entry(&self, app: &str) -> Result<&Entry, TwofaError> {
+        match self.entries.get(app) {
+            Some(entry) => Ok(entry),
+            None => Err(TwofaError::EntryNotFound(app.to_owned())),
+        }
+    }
+
+    /// Adds or replaces `app` as changed at `now`.
+    pub fn insert_entry(&mut self, app: &str, mut entry: Entry, now: u64) {
+        entry.modified = now;
+        self.deleted.remove(app);
+        self.entries.insert(app.to_owned(), entry);
+    }
+
+    /// Removes `app` and remembers it was removed at `now`.
+    pub fn remove_entry(&mut self, app: &str, now: u64) -> Option<Entry> {
+        let entry = self.entries.remove(app)?;
+        self.deleted.insert(app.to_owned(), now);
+        Some(entry)
+    }
+
+    /// Everything but the applications, for backends that store them apart.
+    pub fn header(&self) -> Map<String, Value> {
+        let mut header = self.extra.clone();
+        header.insert(String::from("version"), Value::from(self.version));
+        header.insert(String::from("deleted"), serde_json::json!(self.deleted));
+        header.insert(String::from("merged"), Value::from(self.merged));
+        header
+    }
+}
+
+impl Entry {
+    pub fn new(secret: SecretString, defaults: &DefaultsConfig) -> Self {
+        Self {
+            secret,
+            window: defaults.period,
+            hash: defaults.hash,
+            encoding: defaults.encoding,
+            digits: defaults.digits,
+            issuer: None,
+            modified: 0,
+            extra: Map::new(),
+        }
+    }
+
+    /// Whether both generate the same codes, whenever they were changed.
+    pub fn same_settings(&self, other: &Entry) -> bool {
+        self.secret.expose() == other.secret.expose()
+            && self.window == other.window
+            && self.hash == other.hash
+            && self.encoding == other.encoding
+            && self.digits == other.digits
+            && self.issuer == other.issuer
+            && self.extra == other.extra
+    }
+
+    pub fn validate(&self) -> Result<(), TwofaError> {
+        if self.secret.expose().is_empty() {
+            return Err(TwofaError::CorruptVault("application with an empty secret"));
+        }
+        if self.window == 0 {
+            return Err(TwofaError::CorruptVault("application with a window of 0 seconds"));
+        }
+        if !DIGITS.contains(&self.digits) {
+            return Err(TwofaError::CorruptVault("application with an unsupported number of digits"));
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for Vault {
+    fn drop(&mut self) {
+        for (_, item) in self.extra.iter_mut() {
+            wipe_json(item);
+        }
+    }
+}
+
+impl Drop for Entry {
+    fn drop(&mut self) {
+        for (_, item) in self.extra.iter_mut() {
+            wipe_json(item);
+        }
+    }
+}